@@ -3,5 +3,15 @@
 ~/repos/maelstrom/maelstrom test -w unique-ids --bin target/debug/unique_ids --time-limit 30 --rate 1000 --node-count 3 --availability total --nemesis partition
 ~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/broadcast --node-count 5 --time-limit 20 --rate 10 --nemesis partition
 ~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/broadcast --node-count 25 --time-limit 20 --rate 100
+BROADCAST_TOPOLOGY=tree:4 ~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/broadcast --node-count 25 --time-limit 20 --rate 100 --latency 100
+BROADCAST_MODE=gossip ~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/broadcast --node-count 25 --time-limit 20 --rate 100 --nemesis partition
+BROADCAST_MODE=plumtree BROADCAST_TOPOLOGY=random:4 ~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/broadcast --node-count 25 --time-limit 20 --rate 100 --latency 100 --nemesis partition
+~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/causal_broadcast --node-count 25 --time-limit 20 --rate 100 --latency 100 --latency-dist exponential --nemesis partition
+~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/total_order_broadcast --node-count 5 --time-limit 20 --rate 100 --latency 100 --latency-dist exponential
+TOTAL_ORDER_MODE=consensus ~/repos/maelstrom/maelstrom test -w broadcast --bin target/debug/total_order_broadcast --node-count 5 --time-limit 20 --rate 100 --nemesis partition
 ~/repos/maelstrom/maelstrom test -w g-counter --bin target/debug/grow_only_counter --node-count 3 --rate 100 --time-limit 20 --nemesis partition
 
+~/repos/maelstrom/maelstrom test -w txn-rw-register --bin target/debug/txn_rw_register --node-count 2 --concurrency 2n --time-limit 20 --rate 1000 --consistency-models read-committed --availability total --nemesis partition
+~/repos/maelstrom/maelstrom test -w txn-list-append --bin target/debug/txn_list_append --node-count 3 --time-limit 20 --rate 100 --consistency-models strict-serializable --nemesis partition
+~/repos/maelstrom/maelstrom test -w lin-kv --bin target/debug/lin_kv --node-count 3 --concurrency 2n --time-limit 20 --rate 100 --nemesis partition
+KAFKA_DATA_DIR=$(mktemp -d) ~/repos/maelstrom/maelstrom test -w kafka --bin target/debug/kafka --node-count 2 --concurrency 2n --time-limit 20 --rate 1000
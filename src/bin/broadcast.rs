@@ -1,167 +1,74 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashSet,
+    env,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::time;
 
 use fly_dist_rs::{
     messages::{
-        broadcast::{BroadcastBody, BroadcastOkBody},
+        broadcast::{BroadcastBody, BroadcastOkBody, JsonVal},
         read::{ReadBody, ReadOkBody},
         topology::{TopologyBody, TopologyOkBody},
-        Message, MsgId,
+        Message,
     },
-    node::{Node, NodeId},
+    node::Node,
+    propagate::{self, Mode, PropagateConfig, PropagateMsg, PropagateVal, Propagator},
+    topology::Strategy,
 };
 use serde::{Deserialize, Serialize};
 
-type Val = i32;
+/// Picks how nodes choose their neighbours, see `Strategy::from_str`.
+const TOPOLOGY_ENV: &str = "BROADCAST_TOPOLOGY";
+/// `propagate` (the default) sends values along the topology; `gossip`
+/// pushes them to random peers instead; `plumtree` grows a spanning tree
+/// out of the topology.
+const MODE_ENV: &str = "BROADCAST_MODE";
+const GOSSIP_FANOUT_ENV: &str = "BROADCAST_GOSSIP_FANOUT";
+const GOSSIP_INTERVAL_ENV: &str = "BROADCAST_GOSSIP_INTERVAL_MS";
+/// How long an announced value may be missing before it is grafted.
+const GRAFT_TIMEOUT_ENV: &str = "BROADCAST_GRAFT_TIMEOUT_MS";
+/// How often a node compares digests with a random neighbour.
+const ANTI_ENTROPY_INTERVAL_ENV: &str = "BROADCAST_ANTI_ENTROPY_INTERVAL_MS";
+/// `int` (the default) or `json`.
+const VALUES_ENV: &str = "BROADCAST_VALUES";
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|v| v.parse().ok())
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
-pub enum Body {
+#[serde(bound(serialize = "Val: PropagateVal", deserialize = "Val: PropagateVal"))]
+pub enum Body<Val> {
     Broadcast(BroadcastBody<Val>),
     BroadcastOk(BroadcastOkBody),
     Read(ReadBody),
     ReadOk(ReadOkBody<Val>),
     Topology(TopologyBody),
     TopologyOk(TopologyOkBody),
-    Propagate {
-        msg_id: MsgId,
-        values: HashSet<Val>,
-        known_nodes: HashSet<NodeId>,
-    },
-    PropagateOk {
-        in_reply_to: MsgId,
-    },
-}
-
-#[derive(Clone)]
-pub struct State
-where
-    Self: Send,
-{
-    pub topology: HashMap<NodeId, Vec<NodeId>>,
-    pub values: HashSet<Val>,
-    pub unconfirmed_msgs: HashMap<MsgId, Message<Body>>,
-    pub to_be_sent_vals: HashMap<NodeId, HashSet<Val>>,
-}
-
-type BroadcastNode = Node<Mutex<State>, Body>;
 
-impl State {
-    fn add_new_val(self: &mut Self, val: Val) -> () {
-        self.values.insert(val);
-    }
-}
-
-trait GetState<S>
-where
-    S: Send,
-{
-    fn get_state(self: &Self) -> &S;
+    Propagate(PropagateMsg),
 }
 
-impl GetState<Mutex<State>> for BroadcastNode {
-    fn get_state(self: &Self) -> &Mutex<State> {
-        &self.state.as_ref().unwrap()
+impl<Val> From<PropagateMsg> for Body<Val> {
+    fn from(msg: PropagateMsg) -> Self {
+        Body::Propagate(msg)
     }
 }
 
-trait PropagateMsg {
-    fn ready(self: &Self) -> bool;
-    fn on_recv_val(self: &Self, vals: HashSet<Val>, known_nodes: &HashSet<String>) -> ();
-    fn resend_un_resp_msgs(self: &Self) -> ();
-    fn propagate_to_friends(self: &Self) -> ();
+pub struct State<Val> {
+    pub strategy: Strategy,
+    pub propagator: Propagator<Val>,
 }
 
-impl PropagateMsg for BroadcastNode {
-    fn ready(self: &Self) -> bool {
-        !self.get_state().lock().unwrap().topology.is_empty()
-    }
-    fn on_recv_val(&self, vals: HashSet<Val>, known_nodes: &HashSet<String>) -> () {
-        let node_id = self.node_id();
-
-        for val in vals {
-            let mut state = self.get_state().lock().unwrap();
-            if state.values.contains(&val) {
-                continue;
-            }
-            state.add_new_val(val);
-
-            let topology = &state.topology.to_owned();
-            let friends: &Vec<_> = HashMap::get(topology, node_id).unwrap();
-            let not_known_friends = friends.iter().filter(|&x| !known_nodes.contains(x));
-
-            let to_be_sent_vals = &mut state.to_be_sent_vals;
-
-            not_known_friends.for_each(|friend| {
-                let current_to_be_sent_vals = match to_be_sent_vals.get_mut(friend) {
-                    Some(x) => x,
-                    None => {
-                        to_be_sent_vals.insert(friend.to_string(), HashSet::new());
-                        to_be_sent_vals.get_mut(friend).unwrap()
-                    }
-                };
-
-                current_to_be_sent_vals.insert(val);
-            });
-        }
-    }
-
-    fn resend_un_resp_msgs(self: &Self) {
-        let state = self.get_state().lock().unwrap();
-        let unconfirmed_msgs = &state.unconfirmed_msgs;
+type BroadcastNode<Val> = Node<Mutex<State<Val>>, Body<Val>>;
 
-        for msg in unconfirmed_msgs.values() {
-            self.send_msg(&msg);
-        }
-    }
-
-    fn propagate_to_friends(self: &Self) -> () {
-        if !self.is_init() || !self.ready() {
-            return;
-        }
-
-        let mut state = self.get_state().lock().unwrap();
-        let node_id = self.node_id();
-
-        let topology = &state.topology;
-        let friends: &Vec<_> = HashMap::get(topology, node_id).unwrap();
-        let to_be_sent_vals = state.to_be_sent_vals.to_owned();
-
-        let known_nodes = {
-            let mut known_nodes: HashSet<NodeId> = HashSet::from_iter(friends.to_owned());
-            known_nodes.insert(node_id.clone());
-            known_nodes
-        };
-
-        let mut sent_msgs = Vec::new();
-        for (friend_id, vals) in to_be_sent_vals {
-            let propagate_msg_id = self.next_msg_id();
-            let propagate_body = Body::Propagate {
-                msg_id: propagate_msg_id,
-                values: vals,
-                known_nodes: known_nodes.clone(),
-            };
-
-            let msg = Message {
-                src: node_id.clone(),
-                dest: friend_id.clone(),
-                body: propagate_body.clone(),
-            };
-            self.send_msg(&msg);
-
-            sent_msgs.push((propagate_msg_id, msg));
-        }
-
-        sent_msgs.into_iter().for_each(|(k, v)| {
-            state.unconfirmed_msgs.insert(k, v);
-        });
-    }
-}
-
-pub fn handle_topology(node: &BroadcastNode, msg: Message<Body>) -> () {
+pub fn handle_topology<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
     let (TopologyBody { msg_id, topology }, src, dest) = match msg {
         Message {
             src,
@@ -172,7 +79,9 @@ pub fn handle_topology(node: &BroadcastNode, msg: Message<Body>) -> () {
     };
 
     let mut state = node.state.as_ref().unwrap().lock().unwrap();
-    state.topology = topology;
+    let topology = state.strategy.build(node.node_ids(), &topology);
+    let neighbours = topology.get(node.node_id()).cloned().unwrap_or_default();
+    state.propagator.set_neighbours(neighbours);
 
     let body = Body::TopologyOk(TopologyOkBody {
         in_reply_to: msg_id,
@@ -186,7 +95,10 @@ pub fn handle_topology(node: &BroadcastNode, msg: Message<Body>) -> () {
     })
 }
 
-pub fn handle_broadcast(node: &BroadcastNode, msg: Message<Body>) -> () {
+pub fn handle_broadcast<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
     let (
         BroadcastBody {
             msg_id,
@@ -203,7 +115,9 @@ pub fn handle_broadcast(node: &BroadcastNode, msg: Message<Body>) -> () {
         _ => unreachable!(),
     };
 
-    node.on_recv_val(HashSet::from([value]), &HashSet::new());
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.propagator.insert(HashSet::from([value]));
+    propagate::flush(node, &mut state.propagator);
 
     let body = Body::BroadcastOk(BroadcastOkBody {
         in_reply_to: msg_id,
@@ -216,49 +130,25 @@ pub fn handle_broadcast(node: &BroadcastNode, msg: Message<Body>) -> () {
     })
 }
 
-pub fn handle_propagate(node: &BroadcastNode, msg: Message<Body>) -> () {
-    let (msg_id, values, known_nodes, src, dest) = match msg {
-        Message {
-            src,
-            dest,
-            body:
-                Body::Propagate {
-                    msg_id,
-                    values,
-                    known_nodes,
-                },
-        } => (msg_id, values, known_nodes, src, dest),
-        _ => unreachable!(),
-    };
-
-    node.on_recv_val(values, &known_nodes);
-
-    node.send_msg(&Message {
-        src: dest,
-        dest: src,
-        body: Body::PropagateOk {
-            in_reply_to: msg_id,
-        },
-    })
-}
-
-pub fn handle_propagate_ok(node: &BroadcastNode, msg: Message<Body>) -> () {
-    let (in_reply_to, ..) = match msg {
+pub fn handle_propagate<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (propagate_msg, src) = match msg {
         Message {
             src,
-            dest,
-            body: Body::PropagateOk { in_reply_to },
-        } => (in_reply_to, src, dest),
+            body: Body::Propagate(propagate_msg),
+            ..
+        } => (propagate_msg, src),
         _ => unreachable!(),
     };
 
-    let state = &mut node.get_state().lock().unwrap();
-    let unconfirmed_msgs = &mut state.unconfirmed_msgs;
-
-    unconfirmed_msgs.remove(&in_reply_to);
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.propagator.step(&src, propagate_msg);
+    propagate::flush(node, &mut state.propagator);
 }
 
-pub fn handle_read(node: &BroadcastNode, msg: Message<Body>) -> () {
+pub fn handle_read<Val: PropagateVal>(node: &BroadcastNode<Val>, msg: Message<Body<Val>>) -> () {
     let (ReadBody { msg_id }, src, dest) = match msg {
         Message {
             src,
@@ -268,12 +158,12 @@ pub fn handle_read(node: &BroadcastNode, msg: Message<Body>) -> () {
         _ => unreachable!(),
     };
 
-    let state = node.get_state().lock().unwrap();
+    let state = node.state.as_ref().unwrap().lock().unwrap();
 
     let body = Body::ReadOk(ReadOkBody {
         in_reply_to: msg_id,
         msg_id: node.next_msg_id(),
-        messages: state.values.clone().into_iter().collect(),
+        messages: state.propagator.values().clone().into_iter().collect(),
     });
 
     node.send_msg(&Message {
@@ -283,32 +173,58 @@ pub fn handle_read(node: &BroadcastNode, msg: Message<Body>) -> () {
     })
 }
 
+fn tick<Val: PropagateVal>(node: &BroadcastNode<Val>) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.propagator.tick(Instant::now());
+    propagate::flush(node, &mut state.propagator);
+}
+
 #[tokio::main]
 async fn main() {
-    let state = State {
-        topology: HashMap::new(),
-        values: HashSet::new(),
-        unconfirmed_msgs: HashMap::new(),
-        to_be_sent_vals: HashMap::new(),
+    match env::var(VALUES_ENV).as_deref() {
+        Ok("int") | Err(_) => run::<i32>().await,
+        Ok("json") => run::<JsonVal>().await,
+        Ok(values) => panic!("unknown broadcast values {:?}", values),
+    }
+}
+
+async fn run<Val: PropagateVal>() {
+    let strategy = match env::var(TOPOLOGY_ENV) {
+        Ok(strategy) => strategy.parse().unwrap(),
+        Err(_) => Strategy::default(),
+    };
+    let mode = match env::var(MODE_ENV).as_deref() {
+        Ok("gossip") => Mode::Gossip,
+        Ok("plumtree") => Mode::Plumtree,
+        Ok("propagate") | Err(_) => Mode::Propagate,
+        Ok(mode) => panic!("unknown broadcast mode {:?}", mode),
     };
-    let mut node = Node::new().with_state(Mutex::new(state));
+    let defaults = PropagateConfig::default();
+    let millis =
+        |name: &str, default: Duration| env_var(name).map_or(default, Duration::from_millis);
+    let config = PropagateConfig {
+        mode,
+        gossip_fanout: env_var(GOSSIP_FANOUT_ENV).unwrap_or(defaults.gossip_fanout),
+        gossip_interval: millis(GOSSIP_INTERVAL_ENV, defaults.gossip_interval),
+        graft_timeout: millis(GRAFT_TIMEOUT_ENV, defaults.graft_timeout),
+        anti_entropy_interval: millis(ANTI_ENTROPY_INTERVAL_ENV, defaults.anti_entropy_interval),
+        ..defaults
+    };
+
+    let mut node: BroadcastNode<Val> = Node::new();
 
-    node.add_handler("topology".to_string(), handle_topology);
-    node.add_handler("broadcast".to_string(), handle_broadcast);
-    node.add_handler("read".to_string(), handle_read);
-    node.add_handler("propagate".to_string(), handle_propagate);
-    node.add_handler("propagate_ok".to_string(), handle_propagate_ok);
+    node.add_handler("topology".to_string(), handle_topology::<Val>);
+    node.add_handler("broadcast".to_string(), handle_broadcast::<Val>);
+    node.add_handler("read".to_string(), handle_read::<Val>);
+    node.add_handler("propagate".to_string(), handle_propagate::<Val>);
 
     node.try_init();
-    let node = Arc::new(node);
-    let mut interval = time::interval(time::Duration::from_millis(1000));
-    let resend_node = Arc::clone(&node);
-    let resend_task = tokio::spawn(async move {
-        loop {
-            interval.tick().await;
-            resend_node.resend_un_resp_msgs();
-        }
-    });
+
+    let propagator = Propagator::new(node.node_id().clone(), node.node_ids().clone(), config);
+    let node = Arc::new(node.with_state(Mutex::new(State {
+        strategy,
+        propagator,
+    })));
 
     let main_node = Arc::clone(&node);
     let main_task = tokio::spawn(async move {
@@ -317,15 +233,14 @@ async fn main() {
         }
     });
 
-    let mut interval = time::interval(time::Duration::from_millis(200));
-    let propagate_node = Arc::clone(&node);
-    interval.tick().await;
-    let propagate_task = tokio::spawn(async move {
+    let mut interval = time::interval(time::Duration::from_millis(10));
+    let tick_node = Arc::clone(&node);
+    let tick_task = tokio::spawn(async move {
         loop {
             interval.tick().await;
-            propagate_node.propagate_to_friends();
+            tick(&tick_node);
         }
     });
 
-    let _ = tokio::join!(main_task, resend_task, propagate_task);
+    let _ = tokio::join!(main_task, tick_task);
 }
@@ -0,0 +1,233 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::time;
+
+use fly_dist_rs::{
+    causal::{self, CausalBroadcast},
+    messages::{
+        broadcast::{BroadcastBody, BroadcastOkBody, JsonVal},
+        read::{ReadBody, ReadOkBody},
+        topology::{TopologyBody, TopologyOkBody},
+        Message,
+    },
+    node::Node,
+    propagate::{PropagateConfig, PropagateMsg, PropagateVal},
+    topology::Strategy,
+};
+use serde::{Deserialize, Serialize};
+
+/// Picks how nodes choose their neighbours, see `Strategy::from_str`.
+const TOPOLOGY_ENV: &str = "BROADCAST_TOPOLOGY";
+/// `int` (the default) or `json`.
+const VALUES_ENV: &str = "BROADCAST_VALUES";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[serde(bound(serialize = "Val: PropagateVal", deserialize = "Val: PropagateVal"))]
+pub enum Body<Val> {
+    Broadcast(BroadcastBody<Val>),
+    BroadcastOk(BroadcastOkBody),
+    Read(ReadBody),
+    ReadOk(ReadOkBody<Val>),
+    Topology(TopologyBody),
+    TopologyOk(TopologyOkBody),
+
+    Propagate(PropagateMsg),
+}
+
+impl<Val> From<PropagateMsg> for Body<Val> {
+    fn from(msg: PropagateMsg) -> Self {
+        Body::Propagate(msg)
+    }
+}
+
+pub struct State<Val> {
+    pub strategy: Strategy,
+    pub causal: CausalBroadcast<Val>,
+    /// Every value delivered so far, in delivery order.
+    pub messages: Vec<Val>,
+}
+
+impl<Val: PropagateVal> State<Val> {
+    fn deliver(self: &mut Self) -> () {
+        let delivered = self.causal.take_delivered();
+        self.messages
+            .extend(delivered.into_iter().map(|msg| msg.value));
+    }
+}
+
+type BroadcastNode<Val> = Node<Mutex<State<Val>>, Body<Val>>;
+
+pub fn handle_topology<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (TopologyBody { msg_id, topology }, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Topology(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    let topology = state.strategy.build(node.node_ids(), &topology);
+    let neighbours = topology.get(node.node_id()).cloned().unwrap_or_default();
+    state.causal.set_neighbours(neighbours);
+
+    let body = Body::TopologyOk(TopologyOkBody {
+        in_reply_to: msg_id,
+        msg_id: node.next_msg_id(),
+    });
+
+    node.send_msg(&Message {
+        body,
+        src: dest,
+        dest: src,
+    })
+}
+
+pub fn handle_broadcast<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (
+        BroadcastBody {
+            msg_id,
+            message: value,
+        },
+        src,
+        dest,
+    ) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Broadcast(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.causal.broadcast(value);
+    state.deliver();
+    causal::flush(node, &mut state.causal);
+
+    let body = Body::BroadcastOk(BroadcastOkBody {
+        in_reply_to: msg_id,
+    });
+
+    node.send_msg(&Message {
+        body,
+        src: dest,
+        dest: src,
+    })
+}
+
+pub fn handle_propagate<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (propagate_msg, src) = match msg {
+        Message {
+            src,
+            body: Body::Propagate(propagate_msg),
+            ..
+        } => (propagate_msg, src),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.causal.step(&src, propagate_msg);
+    state.deliver();
+    causal::flush(node, &mut state.causal);
+}
+
+pub fn handle_read<Val: PropagateVal>(node: &BroadcastNode<Val>, msg: Message<Body<Val>>) -> () {
+    let (ReadBody { msg_id }, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Read(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    let state = node.state.as_ref().unwrap().lock().unwrap();
+
+    let body = Body::ReadOk(ReadOkBody {
+        in_reply_to: msg_id,
+        msg_id: node.next_msg_id(),
+        messages: state.messages.clone(),
+    });
+
+    node.send_msg(&Message {
+        body,
+        src: dest,
+        dest: src,
+    })
+}
+
+fn tick<Val: PropagateVal>(node: &BroadcastNode<Val>) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.causal.tick(Instant::now());
+    state.deliver();
+    causal::flush(node, &mut state.causal);
+}
+
+#[tokio::main]
+async fn main() {
+    match env::var(VALUES_ENV).as_deref() {
+        Ok("int") | Err(_) => run::<i32>().await,
+        Ok("json") => run::<JsonVal>().await,
+        Ok(values) => panic!("unknown broadcast values {:?}", values),
+    }
+}
+
+async fn run<Val: PropagateVal>() {
+    let strategy = match env::var(TOPOLOGY_ENV) {
+        Ok(strategy) => strategy.parse().unwrap(),
+        Err(_) => Strategy::default(),
+    };
+
+    let mut node: BroadcastNode<Val> = Node::new();
+
+    node.add_handler("topology".to_string(), handle_topology::<Val>);
+    node.add_handler("broadcast".to_string(), handle_broadcast::<Val>);
+    node.add_handler("read".to_string(), handle_read::<Val>);
+    node.add_handler("propagate".to_string(), handle_propagate::<Val>);
+
+    node.try_init();
+
+    let causal = CausalBroadcast::new(
+        node.node_id().clone(),
+        node.node_ids().clone(),
+        PropagateConfig::default(),
+    );
+    let node = Arc::new(node.with_state(Mutex::new(State {
+        strategy,
+        causal,
+        messages: Vec::new(),
+    })));
+
+    let main_node = Arc::clone(&node);
+    let main_task = tokio::spawn(async move {
+        loop {
+            main_node.one_loop();
+        }
+    });
+
+    let mut interval = time::interval(time::Duration::from_millis(10));
+    let tick_node = Arc::clone(&node);
+    let tick_task = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            tick(&tick_node);
+        }
+    });
+
+    let _ = tokio::join!(main_task, tick_task);
+}
@@ -1,30 +1,121 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    env, fs,
+    hash::{Hash, Hasher},
+    ops::Range,
+    process,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
+use tokio::time;
 
 use fly_dist_rs::{
-    messages::{error::ErrorBody, Message, MsgId},
-    node::Node,
+    log_store::{LogStore, LogStoreConfig},
+    messages::{
+        error::{self, ErrorBody},
+        kv::{KvCasBody, KvCasOkBody, KvReadBody, KvReadOkBody},
+        Message, MsgId,
+    },
+    node::{Node, NodeId},
 };
 use serde::{Deserialize, Serialize};
 
 type Key = String;
 type Val = usize;
 type Offset = usize;
+type Group = String;
+type ProducerId = String;
+type Seq = u64;
+type TxnId = String;
+
+/// Offsets an owner reserves from `lin-kv` with a single CAS.
+const OFFSET_BATCH: Offset = 100;
+/// Bounds of the backoff between reservations and commits that `lin-kv`
+/// failed, e.g. timed out during a partition.
+const LIN_KV_RETRY_MIN: Duration = Duration::from_millis(10);
+const LIN_KV_RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// Sequence numbers an owner remembers per producer and key to answer
+/// retried sends.
+const PRODUCER_WINDOW: usize = 5;
+
+/// Directory the logs are kept in, one subdirectory per node, so a restarted
+/// node finds its own logs again; separate runs need separate directories.
+/// Without it, every process starts from an empty `kafka-{node}-{pid}` in
+/// the temporary directory, and a restarted node recovers nothing.
+const DATA_DIR_ENV: &str = "KAFKA_DATA_DIR";
+const SEGMENT_BYTES_ENV: &str = "KAFKA_SEGMENT_BYTES";
+const RETENTION_MS_ENV: &str = "KAFKA_RETENTION_MS";
+const RETENTION_BYTES_ENV: &str = "KAFKA_RETENTION_BYTES";
+/// Delete log prefixes once the committed offsets of their key passed them,
+/// for every consumer group.
+const DELETE_COMMITTED_ENV: &str = "KAFKA_DELETE_COMMITTED";
+/// Keep only the last offset of every message value. Records carry no key
+/// of their own, so a value is its own identity: compaction drops values
+/// that were sent again, and leaves unique values, like Maelstrom's, alone.
+const COMPACT_ENV: &str = "KAFKA_COMPACT";
+
+const POLL_KEY_MSGS_ENV: &str = "KAFKA_POLL_MAX_MSGS_PER_KEY";
+const POLL_KEY_BYTES_ENV: &str = "KAFKA_POLL_MAX_BYTES_PER_KEY";
+const POLL_MSGS_ENV: &str = "KAFKA_POLL_MAX_MSGS";
+const POLL_BYTES_ENV: &str = "KAFKA_POLL_MAX_BYTES";
+
+/// Outcomes of a batch, decided once by whoever first writes its `lin-kv`
+/// record.
+const TXN_COMMITTED: Val = 1;
+const TXN_ABORTED: Val = 2;
+/// Owners abort batches that stay undecided this long.
+const TXN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `lin-kv` key holding every consumer group that committed an offset,
+/// so retention can read their offsets whichever node they committed
+/// through.
+const GROUPS_KEY: &str = "consumer_groups";
+
+/// Nodes keeping a copy of each key's log, its owner included.
+const REPLICATION_FACTOR_ENV: &str = "KAFKA_REPLICATION_FACTOR";
+/// Replicas that must have a record before its `send_ok`.
+const MIN_INSYNC_REPLICAS_ENV: &str = "KAFKA_MIN_INSYNC_REPLICAS";
+/// Followers that have not acknowledged for this long drop out of the
+/// in-sync set, so sends stop waiting for them.
+const ISR_TIMEOUT: Duration = Duration::from_secs(1);
+/// Replication requests left unanswered this long are sent again.
+const REPLICATE_RETRY: Duration = Duration::from_millis(200);
+const REPLICATE_MAX_MSGS: usize = 1000;
+const REPLICATE_MAX_BYTES: usize = 1 << 20;
+
+const REPLICATION_INTERVAL: Duration = Duration::from_millis(10);
+/// How often nodes tell each other they are alive. Nodes not heard from for
+/// `ISR_TIMEOUT` are taken for dead, and their keys fail over.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A value kept in `lin-kv`: a counter, offset or batch outcome, or the
+/// names under `GROUPS_KEY`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KvVal {
+    Num(Val),
+    Names(BTreeSet<String>),
+}
+
+impl From<Val> for KvVal {
+    fn from(val: Val) -> Self {
+        KvVal::Num(val)
+    }
+}
+
+impl From<BTreeSet<String>> for KvVal {
+    fn from(names: BTreeSet<String>) -> Self {
+        KvVal::Names(names)
+    }
+}
 
 mod lin_kv {
     use crate::*;
 
     pub const ID: &str = "lin-kv";
 
-    pub fn write<F>(node: &KafkaNode, key: Key, value: Val, on_reply: F)
-    where
-        F: 'static + Fn(&KafkaNode, Message<Body>) -> () + Send + Sync,
-    {
-        node.rpc_msg(&msg, on_reply);
-    }
-
     pub fn read<F>(node: &KafkaNode, key: Key, on_reply: F)
     where
         F: 'static + Fn(&KafkaNode, Message<Body>) -> () + Send + Sync,
@@ -32,28 +123,30 @@ mod lin_kv {
         let msg = Message {
             src: node.node_id().to_string(),
             dest: ID.to_string(),
-            body: Body::Read {
+            body: Body::Read(KvReadBody {
                 msg_id: node.next_msg_id(),
                 key,
-            },
+            }),
         };
 
         node.rpc_msg(&msg, on_reply);
     }
 
-    pub fn cas<F>(node: &KafkaNode, key: Key, from: Val, to: Val, on_reply: F)
+    pub fn cas<V, F>(node: &KafkaNode, key: Key, from: V, to: V, create: bool, on_reply: F)
     where
+        V: Into<KvVal>,
         F: 'static + Fn(&KafkaNode, Message<Body>) -> () + Send + Sync,
     {
         let msg = Message {
             src: node.node_id().to_string(),
             dest: ID.to_string(),
-            body: Body::Cas {
+            body: Body::Cas(KvCasBody {
                 msg_id: node.next_msg_id(),
                 key,
-                from,
-                to,
-            },
+                from: from.into(),
+                to: to.into(),
+                create_if_not_exists: Some(create),
+            }),
         };
 
         node.rpc_msg(&msg, on_reply);
@@ -67,6 +160,10 @@ pub enum Body {
         msg_id: MsgId,
         key: Key,
         msg: Val,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer_id: Option<ProducerId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<Seq>,
     },
     SendOk {
         in_reply_to: MsgId,
@@ -76,276 +173,2298 @@ pub enum Body {
         msg_id: MsgId,
         offsets: HashMap<Key, Offset>,
     },
-    PollOk {
+    SendBatch {
+        msg_id: MsgId,
+        msgs: HashMap<Key, Vec<Val>>,
+    },
+    SendBatchOk {
         in_reply_to: MsgId,
-        msgs: HashMap<Key, Vec<(Offset, Val)>>,
+        offsets: HashMap<Key, Vec<Offset>>,
     },
-    CommitOffsets {
+    PrepareBatch {
         msg_id: MsgId,
-        offsets: HashMap<Key, Offset>,
+        txn: TxnId,
+        msgs: HashMap<Key, Vec<Val>>,
     },
-    CommitOffsetsOk {
+    PrepareBatchOk {
         in_reply_to: MsgId,
+        offsets: HashMap<Key, Vec<Offset>>,
     },
-    ListCommittedOffsets {
+    ResolveBatch {
+        txn: TxnId,
+        committed: bool,
+    },
+    Replicate {
         msg_id: MsgId,
-        keys: Vec<Key>,
+        key: Key,
+        /// The records are every record of the leader at or after `from`.
+        from: Offset,
+        records: Vec<(Offset, Val)>,
     },
-    ListCommittedOffsetsOk {
+    ReplicateOk {
         in_reply_to: MsgId,
-        offsets: HashMap<Key, Offset>,
+        next_offset: Offset,
     },
-
-    Read {
+    /// Asks a replica for its records of `key` at or after `from`.
+    CatchUp {
         msg_id: MsgId,
         key: Key,
+        from: Offset,
     },
-    ReadOk {
+    CatchUpOk {
         in_reply_to: MsgId,
-        value: Val,
+        records: Vec<(Offset, Val)>,
+        next_offset: Offset,
     },
-    Write {
-        msg_id: MsgId,
+    Heartbeat {},
+    /// Tells a follower that the owner's log of `key` starts at `before`
+    /// now, so it drops the records retention deleted there.
+    Truncate {
         key: Key,
-        value: Val,
+        before: Offset,
     },
-    WriteOk {
+    PollOk {
         in_reply_to: MsgId,
+        msgs: HashMap<Key, Vec<(Offset, Val)>>,
     },
-    Cas {
+    CommitOffsets {
         msg_id: MsgId,
-        key: Key,
-        from: Val,
-        to: Val,
+        offsets: HashMap<Key, Offset>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<Group>,
+    },
+    CommitOffsetsOk {
+        in_reply_to: MsgId,
+    },
+    ListCommittedOffsets {
+        msg_id: MsgId,
+        keys: Vec<Key>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<Group>,
     },
-    CasOk {
+    ListCommittedOffsetsOk {
         in_reply_to: MsgId,
+        offsets: HashMap<Key, Offset>,
     },
 
+    Read(KvReadBody<Key>),
+    ReadOk(KvReadOkBody<KvVal>),
+    Cas(KvCasBody<Key, KvVal>),
+    CasOk(KvCasOkBody),
+
     Error(ErrorBody),
 }
 
+/// Bounds on what one `poll_ok` carries. Clients poll again from the last
+/// offset they got to read the rest.
+struct PollLimits {
+    key_msgs: usize,
+    key_bytes: usize,
+    msgs: usize,
+    bytes: usize,
+}
+
+impl PollLimits {
+    fn from_env() -> Self {
+        PollLimits {
+            key_msgs: env_var(POLL_KEY_MSGS_ENV).unwrap_or(1000),
+            key_bytes: env_var(POLL_KEY_BYTES_ENV).unwrap_or(1 << 20),
+            msgs: env_var(POLL_MSGS_ENV).unwrap_or(10_000),
+            bytes: env_var(POLL_BYTES_ENV).unwrap_or(4 << 20),
+        }
+    }
+
+    /// Cuts the messages of all keys down to the response limits, handing
+    /// them out one per key in turn so every key gets a share.
+    fn limit_response(
+        self: &Self,
+        msgs: HashMap<Key, Vec<(Offset, Val)>>,
+    ) -> HashMap<Key, Vec<(Offset, Val)>> {
+        let mut sources: Vec<_> = msgs
+            .into_iter()
+            .map(|(key, logs)| (key, logs.into_iter()))
+            .collect();
+        let mut limited: HashMap<Key, Vec<(Offset, Val)>> = sources
+            .iter()
+            .map(|(key, _)| (key.clone(), Vec::new()))
+            .collect();
+
+        let (mut count, mut bytes) = (0, 0);
+        while !sources.is_empty() {
+            sources.retain_mut(|(key, logs)| {
+                let Some(entry) = logs.next() else {
+                    return false;
+                };
+                let len = serde_json::to_vec(&entry).map_or(0, |e| e.len());
+                if count >= self.msgs || (count > 0 && bytes + len > self.bytes) {
+                    return false;
+                }
+
+                count += 1;
+                bytes += len;
+                limited.get_mut(key).unwrap().push(entry);
+                true
+            });
+        }
+
+        limited
+    }
+}
+
+/// A send waiting at the owner for offsets to be reserved.
+#[derive(Clone)]
+struct PendingSend {
+    msg_id: MsgId,
+    value: Val,
+    producer: Option<(ProducerId, Seq)>,
+    src: NodeId,
+    dest: NodeId,
+}
+
+/// Something waiting at the owner for an offset of its key.
+enum Pending {
+    Send(PendingSend),
+    /// A value of a batch being prepared.
+    Staged(TxnId, Val),
+}
+
+/// An offset past the end of a key's stored log. Entries are held back
+/// while an earlier offset is staged, so the log only ever has decided
+/// values and `poll` stops at the first staged offset.
+enum TailEntry {
+    Send(PendingSend),
+    Staged(Val),
+    Committed(TxnId, Val),
+}
+
+/// A record at the owner waiting to be replicated.
+enum Unacked {
+    Send(PendingSend),
+    /// A value of a committed batch.
+    Batch(TxnId),
+}
+
+/// A committed batch whose records are not all replicated yet.
+struct CommittingTxn {
+    /// Records still waiting to be replicated.
+    waiting: usize,
+    offsets: HashMap<Key, Vec<Offset>>,
+}
+
+/// How far the owner got with a producer's send.
+enum SeqState {
+    /// Still waiting for its offset, with the retries that arrived since.
+    Pending(Vec<PendingSend>),
+    Done(Offset),
+}
+
+/// A batch staged at one of its owners, waiting for the decision.
+struct StagedTxn {
+    coordinator: NodeId,
+    msg_id: MsgId,
+    /// Values still waiting for offsets.
+    remaining: usize,
+    offsets: HashMap<Key, Vec<Offset>>,
+    started: Instant,
+}
+
+/// Backoff of a key's failed offset reservations.
+struct ReserveRetry {
+    delay: Duration,
+    /// `None` while the retry is in flight.
+    at: Option<Instant>,
+}
+
+/// Backoff of the commits of a group and key that `lin-kv` failed.
+struct CommitRetry {
+    delay: Duration,
+    /// `None` while the retries are in flight.
+    at: Option<Instant>,
+    /// The offsets to commit, and who waits for each.
+    commits: Vec<(Offset, OnCommitted)>,
+}
+
+struct Replication {
+    factor: usize,
+    min_insync: usize,
+}
+
+impl Replication {
+    fn from_env(node_count: usize) -> Self {
+        let factor = env_var(REPLICATION_FACTOR_ENV)
+            .unwrap_or(3)
+            .clamp(1, node_count);
+        let min_insync = env_var(MIN_INSYNC_REPLICAS_ENV)
+            .unwrap_or(2)
+            .clamp(1, factor);
+
+        Replication { factor, min_insync }
+    }
+}
+
+/// A key this node took over, waiting for its replicas' records before it
+/// is served.
+struct CatchUp {
+    /// Live replicas that may still have records this node lacks, and the
+    /// offset to read each one's log from next. Their logs are read whole,
+    /// since offsets of different owners interleave and the records this
+    /// node lacks may be anywhere below its end.
+    waiting: HashMap<NodeId, Offset>,
+    in_flight: Option<Instant>,
+    /// Requests held back until the key is caught up on.
+    parked: Vec<Message<Body>>,
+}
+
+/// What a key's owner knows about one of its followers.
+#[derive(Default)]
+struct Replica {
+    /// The follower has every record below this offset.
+    end: Offset,
+    last_ack: Option<Instant>,
+    in_flight: Option<Instant>,
+}
+
+/// Each key is owned by one node: the first live one of its replicas, which
+/// are picked by hashing the key over the node ids. The owner keeps the
+/// key's log and commits its offsets; every other node forwards requests
+/// for the key to it. A node that takes a key over, or that lost touch with
+/// its replicas, first catches up on their records. Records it had that an
+/// owner in the meantime lacked stay in its own log only.
+///
+/// Committed offsets are kept per consumer group in `lin-kv` and only ever
+/// move forward. Requests without a group use the default, empty one. Groups
+/// are listed in `lin-kv` before they first commit, and an owner only
+/// deletes records once every group's offset of the key passed them; its
+/// followers cut their copies where it did.
+///
+/// Offsets still come from the `{key}_next_offset` counter in `lin-kv`, so
+/// they stay unique if a key's owner restarts or changes. Owners reserve
+/// them in batches; reserved offsets that never get used are gaps in the
+/// log, which `poll` skips.
+///
+/// Logs live in segment files on disk, so a restarted owner picks its keys
+/// back up from there. Producer sequence numbers are only kept in memory:
+/// after a restart, a retry of a send from before it is appended again.
 pub struct State
 where
     Self: Send,
 {
-    logs_db: HashMap<Key, Vec<Val>>,
-    committed_offsets: HashMap<Key, Offset>,
+    logs: LogStore<Val>,
+    /// The latest sequence numbers of each producer and key.
+    producer_seqs: HashMap<(ProducerId, Key), BTreeMap<Seq, SeqState>>,
+    /// Committed offsets this owner has seen in `lin-kv`.
+    committed_offsets: HashMap<(Group, Key), Offset>,
+    /// Groups known to be listed under `GROUPS_KEY`.
+    groups: HashSet<Group>,
+    /// Where retention last cut the logs this node owns.
+    retained: HashMap<Key, Offset>,
+    /// Last seen value of each key's offset counter in `lin-kv`.
     latest_offsets: HashMap<Key, Offset>,
+    reserved_offsets: HashMap<Key, Range<Offset>>,
+    pending: HashMap<Key, VecDeque<Pending>>,
+    reserving: HashSet<Key>,
+    /// Keys whose last reservation failed, and when to try again.
+    reserve_retries: HashMap<Key, ReserveRetry>,
+    commit_retries: HashMap<(Group, Key), CommitRetry>,
+    /// Staged batch values and the sends behind them. Tails are only kept
+    /// in memory, so an owner that restarts loses its undecided batches.
+    tails: HashMap<Key, BTreeMap<Offset, TailEntry>>,
+    staged_txns: HashMap<TxnId, StagedTxn>,
+    /// Followers of the keys this node owns.
+    replicas: HashMap<Key, HashMap<NodeId, Replica>>,
+    /// Records waiting to be replicated.
+    awaiting_acks: HashMap<Key, BTreeMap<Offset, Unacked>>,
+    committing_txns: HashMap<TxnId, CommittingTxn>,
+    /// Offsets of the committing batches' records, which `poll` stops
+    /// before, so a batch shows up on every key at once.
+    hidden: HashMap<Key, BTreeSet<Offset>>,
+    /// When each other node was last heard from.
+    last_heard: HashMap<NodeId, Instant>,
+    /// Keys this node owns and has caught up on.
+    synced: HashSet<Key>,
+    catching_up: HashMap<Key, CatchUp>,
+    replication: Replication,
+    delete_committed: bool,
+    compact: bool,
+    poll_limits: PollLimits,
+}
+
+impl State {
+    fn take_offset(self: &mut Self, key: &Key) -> Option<Offset> {
+        self.reserved_offsets.get_mut(key)?.next()
+    }
 }
 
 type KafkaNode = Node<Mutex<State>, Body>;
 
-pub fn handle_send(node: &KafkaNode, msg: Message<Body>) -> () {
-    let (msg_id, key, value, src, dest) = match msg {
-        Message {
-            src,
-            dest,
-            body: Body::Send { msg_id, key, msg },
-        } => (msg_id, key, msg, src, dest),
-        _ => unreachable!(),
-    };
+fn owner_index(node: &KafkaNode, key: &Key) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
 
-    {
-        let mut state = node.state.as_ref().unwrap().lock().unwrap();
-        let from = state
-            .latest_offsets
-            .entry(key.clone())
-            .or_insert(0)
-            .to_owned();
-        let to = from.clone() + 1;
+    (hasher.finish() % node.node_ids().len() as u64) as usize
+}
 
-        let cas_key = format!("{}_next_offset", key.clone());
+fn is_live(node: &KafkaNode, state: &State, id: &NodeId) -> bool {
+    id == node.node_id()
+        || state
+            .last_heard
+            .get(id)
+            .is_some_and(|t| t.elapsed() < ISR_TIMEOUT)
+}
 
-        lin_kv::cas(node, cas_key, from, to, move |node, msg| match msg.body {
-            Body::CasOk { .. } => {
-                let mut state = node.state.as_ref().unwrap().lock().unwrap();
-                let logs = state
-                    .logs_db
-                    .entry(key.clone())
-                    .or_insert_with(|| Vec::new());
+/// The nodes keeping copies of the key's log, in the order they take it
+/// over.
+fn replicas<'a>(node: &'a KafkaNode, key: &Key, factor: usize) -> Vec<&'a NodeId> {
+    let node_ids = node.node_ids();
+    let first = owner_index(node, key);
 
-                logs.push(value);
+    (0..factor.min(node_ids.len()))
+        .map(|i| &node_ids[(first + i) % node_ids.len()])
+        .collect()
+}
 
-                let offset = logs.len() - 1;
-                node.send_msg(&Message {
-                    src: dest.clone(),
-                    dest: src.clone(),
-                    body: Body::SendOk {
-                        in_reply_to: msg_id,
-                        offset,
-                    },
-                })
-            }
-            Body::Error(ErrorBody {
-                in_reply_to,
-                code,
-                text,
-            }) => match code {
-                20 => {
-                    let msg = Message {
-                        src: node.node_id().to_string(),
-                        dest: ID.to_string(),
-                        body: Body::Write {
-                            msg_id: node.next_msg_id(),
-                            key,
-                            value,
-                        },
-                    };
-                    node.send_msg(msg);
-                }
-            },
-            _ => unreachable!(),
-        });
-    };
+fn owner<'a>(node: &'a KafkaNode, state: &State, key: &Key) -> &'a NodeId {
+    let replicas = replicas(node, key, state.replication.factor);
+    replicas
+        .iter()
+        .find(|id| is_live(node, state, id))
+        .unwrap_or(&replicas[0])
 }
 
-pub fn handle_poll(node: &KafkaNode, msg: Message<Body>) -> () {
-    let (msg_id, offsets, src, dest) = match msg {
-        Message {
-            src,
-            dest,
-            body: Body::Poll { msg_id, offsets },
-        } => (msg_id, offsets, src, dest),
-        _ => unreachable!(),
-    };
+/// The replicas other than the owner.
+fn followers<'a>(node: &'a KafkaNode, state: &State, key: &Key) -> Vec<&'a NodeId> {
+    let owner = owner(node, state, key);
+    replicas(node, key, state.replication.factor)
+        .into_iter()
+        .filter(|id| *id != owner)
+        .collect()
+}
 
-    let msgs = {
-        let mut msgs = HashMap::new();
-        let state = node.state.as_ref().unwrap().lock().unwrap();
+/// Whether this node owns `key` and has caught up on it.
+fn owns(node: &KafkaNode, state: &State, key: &Key) -> bool {
+    state.synced.contains(key) && owner(node, state, key) == node.node_id()
+}
 
-        for (key, offset) in offsets {
-            let Some(logs) = state.logs_db.get(&key) else {
-                eprintln!("getting non existing key {}", key);
-                continue;
-            };
+/// Whether a request was forwarded by another node. Those are served where
+/// they arrive, so nodes that disagree on an owner do not bounce them.
+fn forwarded(node: &KafkaNode, src: &NodeId) -> bool {
+    node.node_ids().contains(src)
+}
 
-            msgs.insert(
-                key,
-                Vec::from_iter(
-                    logs[offset..]
-                        .iter()
-                        .enumerate()
-                        .map(|(i, v)| (i + offset, v.to_owned())),
-                ),
-            );
-        }
+/// Groups per-key request items by the node owning each key.
+fn split_by_owner<T>(
+    node: &KafkaNode,
+    src: &NodeId,
+    items: impl IntoIterator<Item = (Key, T)>,
+) -> HashMap<NodeId, Vec<(Key, T)>> {
+    let state = node.state.as_ref().unwrap().lock().unwrap();
+    let mut groups: HashMap<NodeId, Vec<(Key, T)>> = HashMap::new();
+    for (key, item) in items {
+        let owner = match forwarded(node, src) {
+            true => node.node_id(),
+            false => owner(node, &state, &key),
+        };
+        groups.entry(owner.clone()).or_default().push((key, item));
+    }
 
-        msgs
-    };
+    groups
+}
 
-    node.send_msg(&Message {
-        src: dest,
-        dest: src,
-        body: Body::PollOk {
-            in_reply_to: msg_id,
-            msgs,
-        },
-    })
+/// Collects the partial replies of the owners involved in one client
+/// request, answering the client once the last of them arrives.
+struct Gather {
+    remaining: usize,
+    msgs: HashMap<Key, Vec<(Offset, Val)>>,
+    offsets: HashMap<Key, Offset>,
+    batch_offsets: HashMap<Key, Vec<Offset>>,
+    /// The code and text of why some part of the request failed, if it did.
+    error: Option<(u32, String)>,
 }
 
-pub fn handle_commit_offsets(node: &KafkaNode, msg: Message<Body>) -> () {
-    let (msg_id, offsets, src, dest) = match msg {
-        Message {
-            src,
-            dest,
-            body: Body::CommitOffsets { msg_id, offsets },
-        } => (msg_id, offsets, src, dest),
-        _ => unreachable!(),
-    };
+impl Gather {
+    fn new(remaining: usize) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Gather {
+            remaining,
+            msgs: HashMap::new(),
+            offsets: HashMap::new(),
+            batch_offsets: HashMap::new(),
+            error: None,
+        }))
+    }
+}
 
-    {
-        let mut state = node.state.as_ref().unwrap().lock().unwrap();
-        for (key, offset) in offsets {
-            state.committed_offsets.insert(key, offset);
-        }
+fn forward<F>(node: &KafkaNode, dest: NodeId, body: Body, on_reply: F) -> ()
+where
+    F: 'static + Fn(&KafkaNode, Message<Body>) -> () + Send + Sync,
+{
+    let msg = Message {
+        src: node.node_id().clone(),
+        dest,
+        body,
     };
 
-    node.send_msg(&Message {
-        src: dest,
-        dest: src,
-        body: Body::CommitOffsetsOk {
-            in_reply_to: msg_id,
-        },
-    })
+    node.rpc_msg(&msg, on_reply);
 }
 
-pub fn handle_list_committed_offsets(node: &KafkaNode, msg: Message<Body>) -> () {
-    let (msg_id, keys, src, dest) = match msg {
-        Message {
-            src,
-            dest,
-            body: Body::ListCommittedOffsets { msg_id, keys },
-        } => (msg_id, keys, src, dest),
-        _ => unreachable!(),
-    };
+fn next_offset_key(key: &Key) -> Key {
+    format!("{}_next_offset", key)
+}
 
-    let offsets = {
-        let state = node.state.as_ref().unwrap().lock().unwrap();
+fn committed_offset_key(group: &Group, key: &Key) -> Key {
+    format!("{}/{}_committed_offset", group, key)
+}
 
-        let mut offsets = HashMap::new();
-        for key in keys {
-            let Some(committed_offset) = state.committed_offsets.get(&key) else {
-                continue;
-            };
+type OnDone = Arc<dyn Fn(&KafkaNode) -> () + Send + Sync>;
+/// Called with the offset already committed when a commit would move it
+/// back.
+type OnCommitted = Arc<dyn Fn(&KafkaNode, Result<(), Offset>) -> () + Send + Sync>;
 
-            offsets.insert(key.clone(), committed_offset.to_owned());
+/// Lists `group` under `GROUPS_KEY` unless this node knows it is. `done` is
+/// called either way; whether it worked shows in `State::groups`.
+fn register_group(node: &KafkaNode, group: Group, done: OnDone) -> () {
+    lin_kv::read(node, GROUPS_KEY.to_string(), move |node, msg| {
+        let groups = match msg.body {
+            Body::ReadOk(KvReadOkBody {
+                value: KvVal::Names(groups),
+                ..
+            }) => groups,
+            Body::Error(ErrorBody { code, .. }) if code == error::KEY_DOES_NOT_EXIST => {
+                BTreeSet::new()
+            }
+            body => {
+                eprintln!("failed to read the consumer groups: {:?}", body);
+                return done(node);
+            }
+        };
+        if groups.contains(&group) {
+            let mut state = node.state.as_ref().unwrap().lock().unwrap();
+            state.groups.extend(groups);
+            drop(state);
+            return done(node);
         }
 
-        offsets
+        let mut listed = groups.clone();
+        listed.insert(group.clone());
+        let (group, done) = (group.clone(), done.clone());
+        lin_kv::cas(
+            node,
+            GROUPS_KEY.to_string(),
+            groups,
+            listed.clone(),
+            true,
+            move |node, msg| match msg.body {
+                Body::CasOk(..) => {
+                    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                    state.groups.extend(listed.iter().cloned());
+                    drop(state);
+                    done(node);
+                }
+                Body::Error(ErrorBody { code, .. }) if code == error::PRECONDITION_FAILED => {
+                    register_group(node, group.clone(), done.clone());
+                }
+                _ => done(node),
+            },
+        );
+    });
+}
+
+/// Raises the committed offset of `key` for `group` to `offset`. Committing
+/// the current offset again succeeds; committing behind it fails.
+fn commit_offset(
+    node: &KafkaNode,
+    group: Group,
+    key: Key,
+    offset: Offset,
+    done: OnCommitted,
+) -> () {
+    let from = {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        if !state.groups.contains(&group) {
+            drop(state);
+            let unlisted = group.clone();
+            let retry: OnDone = Arc::new(move |node| {
+                let (group, key, done) = (group.clone(), key.clone(), done.clone());
+                let listed = node
+                    .state
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .groups
+                    .contains(&group);
+                match listed {
+                    true => commit_offset(node, group, key, offset, done),
+                    false => back_off_commit(node, group, key, offset, done),
+                }
+            });
+            return register_group(node, unlisted, retry);
+        }
+        match state.committed_offsets.get(&(group.clone(), key.clone())) {
+            Some(&committed) if committed > offset => Some(Err(committed)),
+            Some(&committed) if committed == offset => None,
+            committed => Some(Ok(committed.cloned().unwrap_or(0))),
+        }
+    };
+    let from = match from {
+        None => return done(node, Ok(())),
+        Some(Err(committed)) => return done(node, Err(committed)),
+        Some(Ok(from)) => from,
     };
 
-    node.send_msg(&Message {
-        src: dest,
-        dest: src,
-        body: Body::ListCommittedOffsetsOk {
-            in_reply_to: msg_id,
-            offsets,
+    let kv_key = committed_offset_key(&group, &key);
+    lin_kv::cas(
+        node,
+        kv_key.clone(),
+        from,
+        offset,
+        true,
+        move |node, msg| match msg.body {
+            Body::CasOk(..) => {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                let pair = (group.clone(), key.clone());
+                let committed = state.committed_offsets.entry(pair.clone()).or_default();
+                *committed = offset.max(*committed);
+                if state
+                    .commit_retries
+                    .get(&pair)
+                    .is_some_and(|retry| retry.commits.is_empty())
+                {
+                    state.commit_retries.remove(&pair);
+                }
+                drop(state);
+
+                done(node, Ok(()));
+            }
+            Body::Error(ErrorBody { code, .. }) if code == error::PRECONDITION_FAILED => {
+                let (group, key, done) = (group.clone(), key.clone(), done.clone());
+                lin_kv::read(node, kv_key.clone(), move |node, msg| {
+                    let (group, key, done) = (group.clone(), key.clone(), done.clone());
+                    match msg.body {
+                        Body::ReadOk(KvReadOkBody {
+                            value: KvVal::Num(value),
+                            ..
+                        }) => {
+                            let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                            state
+                                .committed_offsets
+                                .insert((group.clone(), key.clone()), value);
+                            drop(state);
+                            commit_offset(node, group, key, offset, done);
+                        }
+                        _ => back_off_commit(node, group, key, offset, done),
+                    }
+                });
+            }
+            Body::Error(ErrorBody {
+                in_reply_to,
+                code,
+                text,
+            }) => {
+                eprintln!(
+                    "Unhandle cas error >> in_reply_to:{},code:{},text:{}",
+                    in_reply_to, code, text
+                );
+                back_off_commit(node, group.clone(), key.clone(), offset, done.clone());
+            }
+            _ => unreachable!(),
         },
-    })
+    );
 }
 
-pub fn handle_error(_node: &KafkaNode, msg: Message<Body>) -> () {
-    let (code, in_reply_to, src, ..) = match msg {
-        Message {
-            src,
-            dest,
-            body: Body::Error(ErrorBody {
-                code, in_reply_to, ..
-            }),
-        } => (code, in_reply_to, src, dest),
-        _ => unreachable!(),
+/// Tries a commit that `lin-kv` failed again once the backoff of its group
+/// and key runs out, doubling the backoff.
+fn back_off_commit(
+    node: &KafkaNode,
+    group: Group,
+    key: Key,
+    offset: Offset,
+    done: OnCommitted,
+) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    let retry = state
+        .commit_retries
+        .entry((group, key))
+        .or_insert(CommitRetry {
+            delay: Duration::ZERO,
+            at: None,
+            commits: Vec::new(),
+        });
+    retry.delay = (retry.delay * 2).clamp(LIN_KV_RETRY_MIN, LIN_KV_RETRY_MAX);
+    retry.at = Some(Instant::now() + retry.delay);
+    retry.commits.push((offset, done));
+}
+
+/// Commits again what is waiting on the groups and keys whose backoff ran
+/// out.
+fn retry_commits(node: &KafkaNode) -> () {
+    let due: Vec<(Group, Key, Vec<(Offset, OnCommitted)>)> = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let now = Instant::now();
+        state
+            .commit_retries
+            .iter_mut()
+            .filter(|(_, retry)| retry.at.is_some_and(|at| at <= now))
+            .map(|((group, key), retry)| {
+                retry.at = None;
+                (
+                    group.clone(),
+                    key.clone(),
+                    std::mem::take(&mut retry.commits),
+                )
+            })
+            .collect()
     };
 
-    match code {
-        _ => eprintln!(
-            "Unhandle error >> src:{},in_reply_to:{},code:{}",
-            src, in_reply_to, code
-        ),
+    for (group, key, commits) in due {
+        for (offset, done) in commits {
+            commit_offset(node, group.clone(), key.clone(), offset, done);
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let state = State {
-        logs_db: HashMap::new(),
-        committed_offsets: HashMap::new(),
-        latest_offsets: HashMap::new(),
-    };
-    let mut node = Node::new().with_state(Mutex::new(state));
+/// Loads the committed offset of `key` for `group` from `lin-kv` unless
+/// this owner already knows it.
+fn load_committed_offset(node: &KafkaNode, group: Group, key: Key, done: OnDone) -> () {
+    {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        if state
+            .committed_offsets
+            .contains_key(&(group.clone(), key.clone()))
+        {
+            drop(state);
+            return done(node);
+        }
+    }
 
-    node.add_handler("send".to_string(), handle_send);
-    node.add_handler("poll".to_string(), handle_poll);
-    node.add_handler("commit_offsets".to_string(), handle_commit_offsets);
-    node.add_handler(
-        "list_committed_offsets".to_string(),
-        handle_list_committed_offsets,
+    lin_kv::read(
+        node,
+        committed_offset_key(&group, &key),
+        move |node, msg| {
+            match msg.body {
+                Body::ReadOk(KvReadOkBody {
+                    value: KvVal::Num(value),
+                    ..
+                }) => {
+                    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                    let committed = state
+                        .committed_offsets
+                        .entry((group.clone(), key.clone()))
+                        .or_default();
+                    *committed = value.max(*committed);
+                }
+                Body::Error(ErrorBody { code, .. }) if code == error::KEY_DOES_NOT_EXIST => (),
+                Body::Error(ErrorBody { code, text, .. }) => {
+                    eprintln!(
+                        "failed to load committed offset of {}: {} {}",
+                        key, code, text
+                    );
+                }
+                _ => unreachable!(),
+            }
+            done(node);
+        },
     );
+}
+
+/// Reserves the next batch of offsets for `key`, then hands them to the
+/// sends and staged values queued for it. Keeps reserving while some are
+/// still waiting.
+fn reserve_offsets(node: &KafkaNode, key: Key) -> () {
+    let from = {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        state.latest_offsets.get(&key).cloned().unwrap_or(0)
+    };
+    let to = from + OFFSET_BATCH;
+
+    lin_kv::cas(
+        node,
+        next_offset_key(&key),
+        from,
+        to,
+        true,
+        move |node, msg| match msg.body {
+            Body::CasOk(..) => {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                state.reserve_retries.remove(&key);
+                state.latest_offsets.insert(key.clone(), to);
+                state.reserved_offsets.insert(key.clone(), from..to);
+
+                let mut replies = Vec::new();
+                while let Some(offset) = state.take_offset(&key) {
+                    let Some(pending) = state.pending.get_mut(&key).and_then(|q| q.pop_front())
+                    else {
+                        state.reserved_offsets.get_mut(&key).unwrap().start = offset;
+                        break;
+                    };
+                    replies.extend(assign_offset(node, &mut state, &key, offset, pending));
+                }
+
+                let waiting = state.pending.get(&key).is_some_and(|q| !q.is_empty());
+                if !waiting {
+                    state.reserving.remove(&key);
+                }
+                drop(state);
+
+                for reply in replies {
+                    node.send_msg(&reply);
+                }
+                if waiting {
+                    reserve_offsets(node, key.clone());
+                }
+            }
+            Body::Error(ErrorBody { code, .. }) if code == error::PRECONDITION_FAILED => {
+                let key = key.clone();
+                lin_kv::read(node, next_offset_key(&key), move |node, msg| {
+                    if let Body::ReadOk(KvReadOkBody {
+                        value: KvVal::Num(value),
+                        ..
+                    }) = msg.body
+                    {
+                        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                        state.latest_offsets.insert(key.clone(), value);
+                    }
+                    reserve_offsets(node, key.clone());
+                });
+            }
+            Body::Error(ErrorBody {
+                in_reply_to,
+                code,
+                text,
+            }) => {
+                eprintln!(
+                    "Unhandle cas error >> in_reply_to:{},code:{},text:{}",
+                    in_reply_to, code, text
+                );
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                let retry = state
+                    .reserve_retries
+                    .entry(key.clone())
+                    .or_insert(ReserveRetry {
+                        delay: Duration::ZERO,
+                        at: None,
+                    });
+                retry.delay = (retry.delay * 2).clamp(LIN_KV_RETRY_MIN, LIN_KV_RETRY_MAX);
+                retry.at = Some(Instant::now() + retry.delay);
+            }
+            _ => unreachable!(),
+        },
+    );
+}
+
+/// Reserves offsets again for the keys whose backoff ran out.
+fn retry_reservations(node: &KafkaNode) -> () {
+    let keys: Vec<Key> = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let now = Instant::now();
+        state
+            .reserve_retries
+            .iter_mut()
+            .filter(|(_, retry)| retry.at.is_some_and(|at| at <= now))
+            .map(|(key, retry)| {
+                retry.at = None;
+                key.clone()
+            })
+            .collect()
+    };
+
+    for key in keys {
+        reserve_offsets(node, key);
+    }
+}
+
+/// Appends a send to its key's log. With replication, the send waits until
+/// enough replicas have the record; otherwise it is answered right away.
+fn append(state: &mut State, key: &Key, offset: Offset, send: PendingSend) -> Vec<Message<Body>> {
+    match state.logs.append(key, offset, &send.value) {
+        Ok(()) if state.replication.factor > 1 => {
+            state
+                .awaiting_acks
+                .entry(key.clone())
+                .or_default()
+                .insert(offset, Unacked::Send(send));
+            Vec::new()
+        }
+        Ok(()) => finish_send(state, key, send, Ok(offset)),
+        Err(e) => {
+            let text = format!("failed to append to {}: {}", key, e);
+            finish_send(state, key, send, Err((error::CRASH, text)))
+        }
+    }
+}
+
+/// Builds the replies to a send and the retries that arrived since. Only
+/// now is its offset handed to later retries; a failed send leaves its
+/// sequence number to the next retry.
+fn finish_send(
+    state: &mut State,
+    key: &Key,
+    send: PendingSend,
+    result: Result<Offset, (u32, String)>,
+) -> Vec<Message<Body>> {
+    let mut retries = Vec::new();
+    if let Some((producer_id, seq)) = &send.producer {
+        let seqs = state
+            .producer_seqs
+            .entry((producer_id.clone(), key.clone()))
+            .or_default();
+        let previous = match result {
+            Ok(offset) => seqs.insert(*seq, SeqState::Done(offset)),
+            Err(_) => seqs.remove(seq),
+        };
+        if let Some(SeqState::Pending(waiting)) = previous {
+            retries = waiting;
+        }
+    }
+
+    std::iter::once(send)
+        .chain(retries)
+        .map(|send| {
+            let body = match &result {
+                Ok(offset) => Body::SendOk {
+                    in_reply_to: send.msg_id,
+                    offset: *offset,
+                },
+                Err((code, text)) => Body::Error(ErrorBody {
+                    in_reply_to: send.msg_id,
+                    code: *code,
+                    text: text.clone(),
+                }),
+            };
+            Message {
+                src: send.dest,
+                dest: send.src,
+                body,
+            }
+        })
+        .collect()
+}
+
+/// Gives `offset` of `key` to a waiting send or staged value. Returns the
+/// replies that became ready.
+fn assign_offset(
+    node: &KafkaNode,
+    state: &mut State,
+    key: &Key,
+    offset: Offset,
+    pending: Pending,
+) -> Vec<Message<Body>> {
+    match pending {
+        Pending::Send(send) => {
+            if let Some(tail) = state.tails.get_mut(key).filter(|t| !t.is_empty()) {
+                tail.insert(offset, TailEntry::Send(send));
+                return Vec::new();
+            }
+
+            append(state, key, offset, send)
+        }
+        Pending::Staged(txn, value) => {
+            // The batch was resolved before its values got here; the
+            // offset stays a gap.
+            let Some(staged) = state.staged_txns.get_mut(&txn) else {
+                return Vec::new();
+            };
+            staged.offsets.entry(key.clone()).or_default().push(offset);
+            staged.remaining -= 1;
+            let reply = (staged.remaining == 0).then(|| Message {
+                src: node.node_id().clone(),
+                dest: staged.coordinator.clone(),
+                body: Body::PrepareBatchOk {
+                    in_reply_to: staged.msg_id,
+                    offsets: staged.offsets.clone(),
+                },
+            });
+
+            state
+                .tails
+                .entry(key.clone())
+                .or_default()
+                .insert(offset, TailEntry::Staged(value));
+            Vec::from_iter(reply)
+        }
+    }
+}
+
+/// Hands offsets of `key` to `pending` as far as reserved ones last, and
+/// queues the rest until more are reserved.
+fn submit(node: &KafkaNode, key: Key, pending: Vec<Pending>) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+
+    let mut replies = Vec::new();
+    let mut pending = pending.into_iter();
+    while let Some(offset) = state.take_offset(&key) {
+        let Some(next) = pending.next() else {
+            state.reserved_offsets.get_mut(&key).unwrap().start = offset;
+            break;
+        };
+        replies.extend(assign_offset(node, &mut state, &key, offset, next));
+    }
+
+    let queue = state.pending.entry(key.clone()).or_default();
+    queue.extend(pending);
+    let start_reserving = !queue.is_empty() && state.reserving.insert(key.clone());
+    drop(state);
+
+    for reply in replies {
+        node.send_msg(&reply);
+    }
+    if start_reserving {
+        reserve_offsets(node, key);
+    }
+}
+
+/// Appends the decided entries at the front of `key`'s tail to its log.
+fn flush_tail(state: &mut State, key: &Key) -> Vec<Message<Body>> {
+    let Some(tail) = state.tails.get_mut(key) else {
+        return Vec::new();
+    };
+    let mut ready = Vec::new();
+    while let Some(entry) = tail.first_entry() {
+        if let TailEntry::Staged(..) = entry.get() {
+            break;
+        }
+        ready.push(entry.remove_entry());
+    }
+
+    let mut replies = Vec::new();
+    for (offset, entry) in ready {
+        match entry {
+            TailEntry::Send(send) => {
+                replies.extend(append(state, key, offset, send));
+            }
+            TailEntry::Committed(txn, value) => match state.logs.append(key, offset, &value) {
+                Ok(()) if state.replication.factor > 1 => {
+                    state
+                        .awaiting_acks
+                        .entry(key.clone())
+                        .or_default()
+                        .insert(offset, Unacked::Batch(txn));
+                }
+                Ok(()) => batch_replicated(state, &txn),
+                Err(e) => {
+                    eprintln!("failed to append committed batch to {}: {}", key, e);
+                    batch_replicated(state, &txn);
+                }
+            },
+            TailEntry::Staged(..) => unreachable!(),
+        }
+    }
+
+    replies
+}
+
+/// Applies the decision on a batch staged here. Aborted values leave gaps.
+fn resolve_txn(state: &mut State, txn: &TxnId, committed: bool) -> Vec<Message<Body>> {
+    let Some(staged) = state.staged_txns.remove(txn) else {
+        return Vec::new();
+    };
+    // Only aborted batches can still have values waiting for offsets.
+    if staged.remaining > 0 {
+        for queue in state.pending.values_mut() {
+            queue.retain(|p| !matches!(p, Pending::Staged(t, _) if t == txn));
+        }
+    }
+
+    if committed {
+        let committing = CommittingTxn {
+            waiting: staged.offsets.values().map(|offsets| offsets.len()).sum(),
+            offsets: staged.offsets.clone(),
+        };
+        for (key, offsets) in &committing.offsets {
+            state.hidden.entry(key.clone()).or_default().extend(offsets);
+        }
+        state.committing_txns.insert(txn.clone(), committing);
+    }
+
+    let mut replies = Vec::new();
+    for (key, offsets) in staged.offsets {
+        let tail = state.tails.get_mut(&key).unwrap();
+        for offset in offsets {
+            if let Some(TailEntry::Staged(value)) = tail.remove(&offset) {
+                if committed {
+                    tail.insert(offset, TailEntry::Committed(txn.clone(), value));
+                }
+            }
+        }
+        replies.extend(flush_tail(state, &key));
+    }
+
+    replies
+}
+
+/// Counts a record of a committed batch as replicated. Once all of them
+/// are, the batch shows up in `poll`.
+fn batch_replicated(state: &mut State, txn: &TxnId) -> () {
+    let Some(committing) = state.committing_txns.get_mut(txn) else {
+        return;
+    };
+    committing.waiting -= 1;
+    if committing.waiting > 0 {
+        return;
+    }
+
+    let committing = state.committing_txns.remove(txn).unwrap();
+    for (key, offsets) in committing.offsets {
+        if let Some(hidden) = state.hidden.get_mut(&key) {
+            for offset in offsets {
+                hidden.remove(&offset);
+            }
+            if hidden.is_empty() {
+                state.hidden.remove(&key);
+            }
+        }
+    }
+}
+
+fn txn_key(txn: &TxnId) -> Key {
+    format!("txn/{}", txn)
+}
+
+type OnDecided = Arc<dyn Fn(&KafkaNode, bool) -> () + Send + Sync>;
+
+/// Proposes an outcome for a batch. The first proposal to reach `lin-kv`
+/// wins; `on_decided` gets whether the batch committed.
+fn decide_txn(node: &KafkaNode, txn: TxnId, proposal: Val, on_decided: OnDecided) -> () {
+    lin_kv::cas(
+        node,
+        txn_key(&txn),
+        0,
+        proposal,
+        true,
+        move |node, msg| match msg.body {
+            Body::CasOk(..) => on_decided(node, proposal == TXN_COMMITTED),
+            Body::Error(ErrorBody { code, .. }) if code == error::PRECONDITION_FAILED => {
+                let (txn, on_decided) = (txn.clone(), on_decided.clone());
+                lin_kv::read(node, txn_key(&txn), move |node, msg| match msg.body {
+                    Body::ReadOk(KvReadOkBody { value, .. }) => {
+                        on_decided(node, value == KvVal::Num(TXN_COMMITTED))
+                    }
+                    _ => decide_txn(node, txn.clone(), proposal, on_decided.clone()),
+                });
+            }
+            Body::Error(ErrorBody {
+                in_reply_to,
+                code,
+                text,
+            }) => {
+                eprintln!(
+                    "Unhandle cas error >> in_reply_to:{},code:{},text:{}",
+                    in_reply_to, code, text
+                );
+                decide_txn(node, txn.clone(), proposal, on_decided.clone());
+            }
+            _ => unreachable!(),
+        },
+    );
+}
+
+/// Checks a producer's send against the sequence numbers already seen for
+/// its key. Returns the reply for a retried send, or `None` for a new one,
+/// which is then tracked as in flight. Retries of a send still in flight
+/// are answered along with it, so they get `Some(None)`.
+fn check_sequence(state: &mut State, key: &Key, send: &PendingSend) -> Option<Option<Body>> {
+    let (producer_id, seq) = send.producer.as_ref()?;
+    let seqs = state
+        .producer_seqs
+        .entry((producer_id.clone(), key.clone()))
+        .or_default();
+
+    match seqs.get_mut(seq) {
+        Some(SeqState::Done(offset)) => {
+            return Some(Some(Body::SendOk {
+                in_reply_to: send.msg_id,
+                offset: *offset,
+            }))
+        }
+        Some(SeqState::Pending(waiting)) => {
+            waiting.push(send.clone());
+            return Some(None);
+        }
+        None => (),
+    }
+
+    if seqs.len() >= PRODUCER_WINDOW && seqs.keys().next().is_some_and(|first| seq < first) {
+        return Some(Some(Body::Error(ErrorBody {
+            in_reply_to: send.msg_id,
+            code: error::PRECONDITION_FAILED,
+            text: format!("sequence number {} of {} expired", seq, producer_id),
+        })));
+    }
+
+    seqs.insert(*seq, SeqState::Pending(Vec::new()));
+    while seqs.len() > PRODUCER_WINDOW {
+        seqs.pop_first();
+    }
+    None
+}
+
+/// Reads each key's log from the given offset, up to the first record of
+/// a batch still being replicated. Offsets that retention already deleted
+/// read from the earliest one still stored.
+fn read_logs(node: &KafkaNode, offsets: Vec<(Key, Offset)>) -> HashMap<Key, Vec<(Offset, Val)>> {
+    let mut msgs = HashMap::new();
+    let state = node.state.as_ref().unwrap().lock().unwrap();
+
+    for (key, offset) in offsets {
+        if !state.logs.contains_key(&key) {
+            eprintln!("getting non existing key {}", key);
+            continue;
+        }
+
+        let limits = &state.poll_limits;
+        match state
+            .logs
+            .read_from(&key, offset, limits.key_msgs, limits.key_bytes)
+        {
+            Ok(mut logs) => {
+                if let Some(hidden) = state.hidden.get(&key).and_then(|h| h.first()) {
+                    logs.retain(|(offset, _)| offset < hidden);
+                }
+                msgs.insert(key, logs);
+            }
+            Err(e) => eprintln!("failed to read {} from {}: {}", key, offset, e),
+        }
+    }
+
+    msgs
+}
+
+pub fn handle_send(node: &KafkaNode, msg: Message<Body>) -> () {
+    let Some(msg) = await_sync(node, msg) else {
+        return;
+    };
+    let (key, send) = match msg {
+        Message {
+            src,
+            dest,
+            body:
+                Body::Send {
+                    msg_id,
+                    key,
+                    msg,
+                    producer_id,
+                    seq,
+                },
+        } => (
+            key,
+            PendingSend {
+                msg_id,
+                value: msg,
+                producer: producer_id.zip(seq),
+                src,
+                dest,
+            },
+        ),
+        _ => unreachable!(),
+    };
+
+    let owner = match forwarded(node, &send.src) {
+        true => node.node_id().clone(),
+        false => owner(node, &node.state.as_ref().unwrap().lock().unwrap(), &key).clone(),
+    };
+    if owner == *node.node_id() {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        if let Some(reply) = check_sequence(&mut state, &key, &send) {
+            drop(state);
+
+            if let Some(body) = reply {
+                node.send_msg(&Message {
+                    src: send.dest,
+                    dest: send.src,
+                    body,
+                });
+            }
+            return;
+        }
+
+        drop(state);
+
+        return submit(node, key, vec![Pending::Send(send)]);
+    }
+
+    let PendingSend {
+        msg_id,
+        value,
+        producer,
+        src,
+        dest,
+    } = send;
+    let (producer_id, seq) = producer.unzip();
+    let body = Body::Send {
+        msg_id: node.next_msg_id(),
+        key,
+        msg: value,
+        producer_id,
+        seq,
+    };
+    forward(node, owner, body, move |node, msg| {
+        let body = match msg.body {
+            Body::SendOk { offset, .. } => Body::SendOk {
+                in_reply_to: msg_id,
+                offset,
+            },
+            Body::Error(ErrorBody { code, text, .. }) => Body::Error(ErrorBody {
+                in_reply_to: msg_id,
+                code,
+                text,
+            }),
+            _ => return eprintln!("unexpected reply to forwarded send {:?}", msg),
+        };
+
+        node.send_msg(&Message {
+            src: dest.clone(),
+            dest: src.clone(),
+            body,
+        })
+    });
+}
+
+/// Coordinates a batch: every owner involved stages its values, then the
+/// batch commits only if no owner gave up on it first.
+pub fn handle_send_batch(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (msg_id, msgs, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::SendBatch { msg_id, msgs },
+        } => (msg_id, msgs, src, dest),
+        _ => unreachable!(),
+    };
+
+    let txn = format!("{}-{}", node.node_id(), node.next_msg_id());
+    let groups = split_by_owner(node, &src, msgs);
+    let owners: Vec<NodeId> = groups.keys().cloned().collect();
+
+    let gather = Gather::new(groups.len());
+    let on_decided: OnDecided = {
+        let (txn, gather) = (txn.clone(), gather.clone());
+        Arc::new(move |node: &KafkaNode, committed| {
+            for owner in &owners {
+                node.send_msg(&Message {
+                    src: node.node_id().clone(),
+                    dest: owner.clone(),
+                    body: Body::ResolveBatch {
+                        txn: txn.clone(),
+                        committed,
+                    },
+                });
+            }
+
+            let body = match committed {
+                true => Body::SendBatchOk {
+                    in_reply_to: msg_id,
+                    offsets: std::mem::take(&mut gather.lock().unwrap().batch_offsets),
+                },
+                false => Body::Error(ErrorBody {
+                    in_reply_to: msg_id,
+                    code: error::TXN_CONFLICT,
+                    text: "batch was aborted".to_string(),
+                }),
+            };
+            node.send_msg(&Message {
+                src: dest.clone(),
+                dest: src.clone(),
+                body,
+            });
+        })
+    };
+    if groups.is_empty() {
+        return on_decided(node, true);
+    }
+
+    for (owner, msgs) in groups {
+        let body = Body::PrepareBatch {
+            msg_id: node.next_msg_id(),
+            txn: txn.clone(),
+            msgs: msgs.into_iter().collect(),
+        };
+        let (txn, gather, on_decided) = (txn.clone(), gather.clone(), on_decided.clone());
+        forward(node, owner, body, move |node, msg| {
+            let mut gather = gather.lock().unwrap();
+            if gather.remaining == 0 {
+                return;
+            }
+            let proposal = match msg.body {
+                Body::PrepareBatchOk { offsets, .. } => {
+                    gather.batch_offsets.extend(offsets);
+                    gather.remaining -= 1;
+                    if gather.remaining > 0 {
+                        return;
+                    }
+                    TXN_COMMITTED
+                }
+                _ => {
+                    gather.remaining = 0;
+                    TXN_ABORTED
+                }
+            };
+            drop(gather);
+
+            decide_txn(node, txn.clone(), proposal, on_decided.clone());
+        });
+    }
+}
+
+pub fn handle_prepare_batch(node: &KafkaNode, msg: Message<Body>) -> () {
+    let Some(msg) = await_sync(node, msg) else {
+        return;
+    };
+    let (msg_id, txn, msgs, src) = match msg {
+        Message {
+            src,
+            body: Body::PrepareBatch { msg_id, txn, msgs },
+            ..
+        } => (msg_id, txn, msgs, src),
+        _ => unreachable!(),
+    };
+
+    // Keys without values are answered with no offsets.
+    let offsets: HashMap<Key, Vec<Offset>> =
+        msgs.keys().map(|key| (key.clone(), Vec::new())).collect();
+    let remaining = msgs.values().map(|values| values.len()).sum();
+    if remaining == 0 {
+        return node.send_msg(&Message {
+            src: node.node_id().clone(),
+            dest: src,
+            body: Body::PrepareBatchOk {
+                in_reply_to: msg_id,
+                offsets,
+            },
+        });
+    }
+
+    {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        state.staged_txns.insert(
+            txn.clone(),
+            StagedTxn {
+                coordinator: src,
+                msg_id,
+                remaining,
+                offsets,
+                started: Instant::now(),
+            },
+        );
+    }
+
+    for (key, values) in msgs {
+        let staged = values
+            .into_iter()
+            .map(|value| Pending::Staged(txn.clone(), value))
+            .collect();
+        submit(node, key, staged);
+    }
+}
+
+pub fn handle_resolve_batch(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (txn, committed) = match msg {
+        Message {
+            body: Body::ResolveBatch { txn, committed },
+            ..
+        } => (txn, committed),
+        _ => unreachable!(),
+    };
+
+    let replies = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        resolve_txn(&mut state, &txn, committed)
+    };
+    for reply in replies {
+        node.send_msg(&reply);
+    }
+}
+
+/// Aborts the batches staged here whose coordinator went quiet, unless it
+/// already committed them.
+fn expire_txns(node: &KafkaNode) -> () {
+    let expired: Vec<TxnId> = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        state
+            .staged_txns
+            .iter_mut()
+            .filter(|(_, staged)| staged.started.elapsed() > TXN_TIMEOUT)
+            .map(|(txn, staged)| {
+                staged.started = Instant::now();
+                txn.clone()
+            })
+            .collect()
+    };
+
+    for txn in expired {
+        let resolved = txn.clone();
+        let on_decided: OnDecided = Arc::new(move |node: &KafkaNode, committed| {
+            let replies = {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                resolve_txn(&mut state, &resolved, committed)
+            };
+            for reply in replies {
+                node.send_msg(&reply);
+            }
+        });
+        decide_txn(node, txn, TXN_ABORTED, on_decided);
+    }
+}
+
+/// Releases the records that reached every in-sync follower and at least
+/// `min_insync` replicas: replies to sends, and batches once all of their
+/// records did.
+fn release_acks(state: &mut State, key: &Key) -> Vec<Message<Body>> {
+    let Some(awaiting) = state.awaiting_acks.get_mut(key) else {
+        return Vec::new();
+    };
+    let replicas = state.replicas.get(key);
+    let followers = || replicas.into_iter().flat_map(|r| r.values());
+
+    let mut acked = Vec::new();
+    while let Some(entry) = awaiting.first_entry() {
+        let offset = *entry.key();
+        let copies = 1 + followers().filter(|r| r.end > offset).count();
+        let in_sync_behind = followers()
+            .any(|r| r.end <= offset && r.last_ack.is_some_and(|t| t.elapsed() < ISR_TIMEOUT));
+        if copies < state.replication.min_insync || in_sync_behind {
+            break;
+        }
+        acked.push(entry.remove_entry());
+    }
+
+    let mut replies = Vec::new();
+    for (offset, unacked) in acked {
+        match unacked {
+            Unacked::Send(send) => replies.extend(finish_send(state, key, send, Ok(offset))),
+            Unacked::Batch(txn) => batch_replicated(state, &txn),
+        }
+    }
+
+    replies
+}
+
+/// Sends every follower of the keys this node owns the records it is
+/// missing, starting from the last offset it acknowledged. This also
+/// catches followers up after a partition heals. Sends on keys this node
+/// no longer owns, or must catch up on again, fail: a new owner need not
+/// have their records. Batches stop waiting on such keys, since their
+/// polls go to the new owner.
+fn replicate_logs(node: &KafkaNode) -> () {
+    let mut requests = Vec::new();
+    let mut replies = Vec::new();
+    {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let state = &mut *state;
+
+        let lost: Vec<Key> = state
+            .awaiting_acks
+            .keys()
+            .filter(|key| !owns(node, state, key))
+            .cloned()
+            .collect();
+        for key in lost {
+            let text = format!("lost {} before the send was replicated", key);
+            for unacked in state.awaiting_acks.remove(&key).unwrap().into_values() {
+                match unacked {
+                    Unacked::Send(send) => {
+                        let result = Err((error::TIMEOUT, text.clone()));
+                        replies.extend(finish_send(state, &key, send, result));
+                    }
+                    Unacked::Batch(txn) => batch_replicated(state, &txn),
+                }
+            }
+        }
+
+        let keys: Vec<Key> = state
+            .logs
+            .keys()
+            .filter(|key| owns(node, state, key))
+            .cloned()
+            .collect();
+        for key in keys {
+            let end = state.logs.next_offset(&key).unwrap_or(0);
+            let followers: Vec<NodeId> =
+                followers(node, state, &key).into_iter().cloned().collect();
+            for follower in followers {
+                let replica = state
+                    .replicas
+                    .entry(key.clone())
+                    .or_default()
+                    .entry(follower.clone())
+                    .or_default();
+                let waiting = replica
+                    .in_flight
+                    .is_some_and(|t| t.elapsed() < REPLICATE_RETRY);
+                if replica.end >= end || waiting {
+                    continue;
+                }
+
+                let from = replica.end;
+                match state
+                    .logs
+                    .read_from(&key, from, REPLICATE_MAX_MSGS, REPLICATE_MAX_BYTES)
+                {
+                    Ok(records) => {
+                        replica.in_flight = Some(Instant::now());
+                        requests.push((follower, key.clone(), from, records));
+                    }
+                    Err(e) => eprintln!("failed to read {} for replication: {}", key, e),
+                }
+            }
+            replies.extend(release_acks(state, &key));
+        }
+    }
+
+    for reply in replies {
+        node.send_msg(&reply);
+    }
+    for (follower, key, from, records) in requests {
+        let body = Body::Replicate {
+            msg_id: node.next_msg_id(),
+            key: key.clone(),
+            from,
+            records,
+        };
+        let replica_id = follower.clone();
+        forward(node, follower, body, move |node, msg| {
+            let Body::ReplicateOk { next_offset, .. } = msg.body else {
+                return;
+            };
+
+            let replies = {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                let replicas = state.replicas.entry(key.clone()).or_default();
+                let replica = replicas.entry(replica_id.clone()).or_default();
+                replica.end = next_offset;
+                replica.last_ack = Some(Instant::now());
+                replica.in_flight = None;
+                release_acks(&mut state, &key)
+            };
+            for reply in replies {
+                node.send_msg(&reply);
+            }
+        });
+    }
+}
+
+/// Appends the records past the end of this node's copy of the log.
+fn append_records(state: &mut State, key: &Key, records: Vec<(Offset, Val)>) -> () {
+    let end = state.logs.next_offset(key).unwrap_or(0);
+    for (offset, value) in records.into_iter().filter(|(o, _)| *o >= end) {
+        if let Err(e) = state.logs.append(key, offset, &value) {
+            eprintln!("failed to append replicated {}: {}", key, e);
+            break;
+        }
+    }
+}
+
+/// Appends the records a key's owner sent, if they continue this node's
+/// copy of the log. Either way, tells the owner where the copy ends.
+pub fn handle_replicate(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (msg_id, key, from, records, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body:
+                Body::Replicate {
+                    msg_id,
+                    key,
+                    from,
+                    records,
+                },
+        } => (msg_id, key, from, records, src, dest),
+        _ => unreachable!(),
+    };
+
+    let next_offset = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        if state.logs.next_offset(&key).unwrap_or(0) >= from {
+            append_records(&mut state, &key, records);
+        }
+        state.logs.next_offset(&key).unwrap_or(0)
+    };
+
+    node.send_msg(&Message {
+        src: dest,
+        dest: src,
+        body: Body::ReplicateOk {
+            in_reply_to: msg_id,
+            next_offset,
+        },
+    });
+}
+
+/// Sends a replica's records of a key to a node catching up on it.
+pub fn handle_catch_up(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (msg_id, key, from, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::CatchUp { msg_id, key, from },
+        } => (msg_id, key, from, src, dest),
+        _ => unreachable!(),
+    };
+
+    let (records, next_offset) = {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        let records = match state.logs.contains_key(&key) {
+            true => state
+                .logs
+                .read_from(&key, from, REPLICATE_MAX_MSGS, REPLICATE_MAX_BYTES)
+                .unwrap_or_else(|e| {
+                    eprintln!("failed to read {} for catching up: {}", key, e);
+                    Vec::new()
+                }),
+            false => Vec::new(),
+        };
+        (records, state.logs.next_offset(&key).unwrap_or(0))
+    };
+
+    node.send_msg(&Message {
+        src: dest,
+        dest: src,
+        body: Body::CatchUpOk {
+            in_reply_to: msg_id,
+            records,
+            next_offset,
+        },
+    });
+}
+
+/// Holds `msg` back until this node caught up on the keys it owns among
+/// those the request touches, and starts catching up on them. Returns
+/// `msg` if it can be served now.
+fn await_sync(node: &KafkaNode, msg: Message<Body>) -> Option<Message<Body>> {
+    let keys: Vec<&Key> = match &msg.body {
+        Body::Send { key, .. } => vec![key],
+        Body::Poll { offsets, .. } => offsets.keys().collect(),
+        Body::PrepareBatch { msgs, .. } => msgs.keys().collect(),
+        _ => Vec::new(),
+    };
+
+    {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let unsynced: Vec<Key> = keys
+            .into_iter()
+            .filter(|key| !state.synced.contains(*key))
+            .filter(|key| owner(node, &state, key) == node.node_id())
+            .cloned()
+            .collect();
+        let Some(first) = unsynced.first().cloned() else {
+            return Some(msg);
+        };
+
+        for key in unsynced {
+            if state.catching_up.contains_key(&key) {
+                continue;
+            }
+            let waiting = followers(node, &state, &key)
+                .into_iter()
+                .filter(|id| is_live(node, &state, id))
+                .map(|id| (id.clone(), 0))
+                .collect();
+            let catch_up = CatchUp {
+                waiting,
+                in_flight: None,
+                parked: Vec::new(),
+            };
+            state.catching_up.insert(key, catch_up);
+        }
+        state.catching_up.get_mut(&first).unwrap().parked.push(msg);
+    }
+
+    catch_up(node);
+    None
+}
+
+/// Asks the replicas of the keys this node is catching up on for the
+/// records it lacks, and serves the requests held back on keys it caught up
+/// on. Replicas that died meanwhile are not waited for.
+fn catch_up(node: &KafkaNode) -> () {
+    let mut requests = Vec::new();
+    let mut parked = Vec::new();
+    {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let state = &mut *state;
+        let live: HashSet<NodeId> = node
+            .node_ids()
+            .iter()
+            .filter(|id| is_live(node, state, id))
+            .cloned()
+            .collect();
+
+        let mut caught_up = Vec::new();
+        for (key, catch_up) in state.catching_up.iter_mut() {
+            catch_up.waiting.retain(|id, _| live.contains(id));
+            if catch_up.waiting.is_empty() {
+                caught_up.push(key.clone());
+                continue;
+            }
+            if catch_up
+                .in_flight
+                .is_some_and(|t| t.elapsed() < REPLICATE_RETRY)
+            {
+                continue;
+            }
+
+            catch_up.in_flight = Some(Instant::now());
+            for (replica, from) in &catch_up.waiting {
+                requests.push((replica.clone(), key.clone(), *from));
+            }
+        }
+
+        // Offsets reserved before may be behind the records caught up on,
+        // and followers are asked afresh where their copies end.
+        for key in caught_up {
+            let catch_up = state.catching_up.remove(&key).unwrap();
+            parked.extend(catch_up.parked);
+            state.reserved_offsets.remove(&key);
+            state.replicas.remove(&key);
+            state.synced.insert(key);
+        }
+    }
+
+    for (replica, key, from) in requests {
+        let body = Body::CatchUp {
+            msg_id: node.next_msg_id(),
+            key: key.clone(),
+            from,
+        };
+        let replica_id = replica.clone();
+        forward(node, replica, body, move |node, msg| {
+            let Body::CatchUpOk {
+                records,
+                next_offset,
+                ..
+            } = msg.body
+            else {
+                return;
+            };
+
+            {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                let read = records.last().map(|(offset, _)| offset + 1);
+                if let Err(e) = state.logs.insert(&key, records) {
+                    eprintln!("failed to insert caught up {}: {}", key, e);
+                }
+                let Some(catch_up) = state.catching_up.get_mut(&key) else {
+                    return;
+                };
+                match read.filter(|read| *read < next_offset) {
+                    Some(read) => {
+                        if let Some(from) = catch_up.waiting.get_mut(&replica_id) {
+                            *from = read.max(*from);
+                        }
+                    }
+                    None => {
+                        catch_up.waiting.remove(&replica_id);
+                    }
+                }
+                catch_up.in_flight = None;
+            }
+            catch_up(node);
+        });
+    }
+    for msg in parked {
+        dispatch(node, msg);
+    }
+}
+
+/// Serves a request that was held back.
+fn dispatch(node: &KafkaNode, msg: Message<Body>) -> () {
+    match msg.body {
+        Body::Send { .. } => handle_send(node, msg),
+        Body::Poll { .. } => handle_poll(node, msg),
+        Body::PrepareBatch { .. } => handle_prepare_batch(node, msg),
+        _ => unreachable!(),
+    }
+}
+
+fn heartbeat(node: &KafkaNode) -> () {
+    for peer in node.node_ids() {
+        if peer != node.node_id() {
+            node.send_msg(&Message {
+                src: node.node_id().clone(),
+                dest: peer.clone(),
+                body: Body::Heartbeat {},
+            });
+        }
+    }
+}
+
+/// Notes that a node is alive. A replica that comes back may have records
+/// this node lacks, or may take its keys back, so the keys they share are
+/// caught up on again.
+pub fn handle_heartbeat(node: &KafkaNode, msg: Message<Body>) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    if !is_live(node, &state, &msg.src) {
+        let factor = state.replication.factor;
+        state
+            .synced
+            .retain(|key| !replicas(node, key, factor).contains(&&msg.src));
+    }
+    state.last_heard.insert(msg.src, Instant::now());
+}
+
+pub fn handle_poll(node: &KafkaNode, msg: Message<Body>) -> () {
+    let Some(msg) = await_sync(node, msg) else {
+        return;
+    };
+    let (msg_id, offsets, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Poll { msg_id, offsets },
+        } => (msg_id, offsets, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut groups = split_by_owner(node, &src, offsets);
+    let local = groups.remove(node.node_id()).unwrap_or_default();
+
+    let gather = Gather::new(groups.len() + 1);
+    let reply = Arc::new(move |node: &KafkaNode, gather: &Mutex<Gather>| {
+        let mut gather = gather.lock().unwrap();
+        gather.remaining -= 1;
+        if gather.remaining > 0 {
+            return;
+        }
+
+        let msgs = {
+            let state = node.state.as_ref().unwrap().lock().unwrap();
+            state
+                .poll_limits
+                .limit_response(std::mem::take(&mut gather.msgs))
+        };
+
+        node.send_msg(&Message {
+            src: dest.clone(),
+            dest: src.clone(),
+            body: Body::PollOk {
+                in_reply_to: msg_id,
+                msgs,
+            },
+        })
+    });
+
+    for (owner, offsets) in groups {
+        let body = Body::Poll {
+            msg_id: node.next_msg_id(),
+            offsets: offsets.into_iter().collect(),
+        };
+        let (gather, reply) = (gather.clone(), reply.clone());
+        forward(node, owner, body, move |node, msg| {
+            if let Body::PollOk { msgs, .. } = msg.body {
+                gather.lock().unwrap().msgs.extend(msgs);
+            }
+            reply(node, &gather);
+        });
+    }
+
+    let msgs = read_logs(node, local);
+    gather.lock().unwrap().msgs.extend(msgs);
+    reply(node, &gather);
+}
+
+pub fn handle_commit_offsets(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (msg_id, offsets, group, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body:
+                Body::CommitOffsets {
+                    msg_id,
+                    offsets,
+                    group,
+                },
+        } => (msg_id, offsets, group, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut groups = split_by_owner(node, &src, offsets);
+    let local = groups.remove(node.node_id()).unwrap_or_default();
+
+    // One reply per owner and local key, plus one once all were dispatched.
+    // Keys that do not move back are committed even if another key does.
+    let gather = Gather::new(groups.len() + local.len() + 1);
+    let reply = Arc::new(move |node: &KafkaNode, gather: &Mutex<Gather>| {
+        let mut gather = gather.lock().unwrap();
+        gather.remaining -= 1;
+        if gather.remaining > 0 {
+            return;
+        }
+
+        let body = match gather.error.take() {
+            Some((code, text)) => Body::Error(ErrorBody {
+                in_reply_to: msg_id,
+                code,
+                text,
+            }),
+            None => Body::CommitOffsetsOk {
+                in_reply_to: msg_id,
+            },
+        };
+        node.send_msg(&Message {
+            src: dest.clone(),
+            dest: src.clone(),
+            body,
+        })
+    });
+
+    for (owner, offsets) in groups {
+        let body = Body::CommitOffsets {
+            msg_id: node.next_msg_id(),
+            offsets: offsets.into_iter().collect(),
+            group: group.clone(),
+        };
+        let (gather, reply) = (gather.clone(), reply.clone());
+        forward(node, owner, body, move |node, msg| {
+            if let Body::Error(ErrorBody { code, text, .. }) = msg.body {
+                gather.lock().unwrap().error = Some((code, text));
+            }
+            reply(node, &gather);
+        });
+    }
+
+    let group = group.unwrap_or_default();
+    for (key, offset) in local {
+        let (gather, reply, failed_key) = (gather.clone(), reply.clone(), key.clone());
+        let done: OnCommitted = Arc::new(move |node, result| {
+            if let Err(committed) = result {
+                let text = format!(
+                    "offset {} of {} is behind the committed offset {}",
+                    offset, failed_key, committed
+                );
+                gather.lock().unwrap().error = Some((error::PRECONDITION_FAILED, text));
+            }
+            reply(node, &gather);
+        });
+        commit_offset(node, group.clone(), key, offset, done);
+    }
+    reply(node, &gather);
+}
+
+pub fn handle_list_committed_offsets(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (msg_id, keys, group, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body:
+                Body::ListCommittedOffsets {
+                    msg_id,
+                    keys,
+                    group,
+                },
+        } => (msg_id, keys, group, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut groups = split_by_owner(node, &src, keys.into_iter().map(|key| (key, ())));
+    let local = groups.remove(node.node_id()).unwrap_or_default();
+
+    let gather = Gather::new(groups.len() + local.len() + 1);
+    let reply = Arc::new(move |node: &KafkaNode, gather: &Mutex<Gather>| {
+        let mut gather = gather.lock().unwrap();
+        gather.remaining -= 1;
+        if gather.remaining > 0 {
+            return;
+        }
+
+        node.send_msg(&Message {
+            src: dest.clone(),
+            dest: src.clone(),
+            body: Body::ListCommittedOffsetsOk {
+                in_reply_to: msg_id,
+                offsets: std::mem::take(&mut gather.offsets),
+            },
+        })
+    });
+
+    for (owner, keys) in groups {
+        let body = Body::ListCommittedOffsets {
+            msg_id: node.next_msg_id(),
+            keys: keys.into_iter().map(|(key, _)| key).collect(),
+            group: group.clone(),
+        };
+        let (gather, reply) = (gather.clone(), reply.clone());
+        forward(node, owner, body, move |node, msg| {
+            if let Body::ListCommittedOffsetsOk { offsets, .. } = msg.body {
+                gather.lock().unwrap().offsets.extend(offsets);
+            }
+            reply(node, &gather);
+        });
+    }
+
+    let group = group.unwrap_or_default();
+    for (key, _) in local {
+        let (gather, reply) = (gather.clone(), reply.clone());
+        let (group_key, committed_key) = (group.clone(), key.clone());
+        let done: OnDone = Arc::new(move |node| {
+            let committed = {
+                let state = node.state.as_ref().unwrap().lock().unwrap();
+                let group_key = (group_key.clone(), committed_key.clone());
+                state.committed_offsets.get(&group_key).cloned()
+            };
+            if let Some(offset) = committed {
+                gather
+                    .lock()
+                    .unwrap()
+                    .offsets
+                    .insert(committed_key.clone(), offset);
+            }
+            reply(node, &gather);
+        });
+        load_committed_offset(node, group.clone(), key, done);
+    }
+    reply(node, &gather);
+}
+
+pub fn handle_error(_node: &KafkaNode, msg: Message<Body>) -> () {
+    let (code, in_reply_to, src, ..) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Error(ErrorBody {
+                code, in_reply_to, ..
+            }),
+        } => (code, in_reply_to, src, dest),
+        _ => unreachable!(),
+    };
+
+    match code {
+        _ => eprintln!(
+            "Unhandle error >> src:{},in_reply_to:{},code:{}",
+            src, in_reply_to, code
+        ),
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+/// The lowest offset every listed group committed on each key, as read
+/// from `lin-kv` for one round of retention.
+struct CommittedScan {
+    remaining: usize,
+    lowest: HashMap<Key, Offset>,
+    /// Some offset could not be read, so none may hold back deletion.
+    failed: bool,
+}
+
+/// Applies retention and compaction to the logs this node owns. With
+/// `delete_committed`, the committed offsets of every listed group are read
+/// from `lin-kv` first.
+fn maintain_logs(node: &KafkaNode) -> () {
+    let (keys, delete_committed) = {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        let keys: Vec<Key> = state
+            .logs
+            .keys()
+            .filter(|key| owns(node, &state, key))
+            .cloned()
+            .collect();
+        (keys, state.delete_committed)
+    };
+    if !delete_committed || keys.is_empty() {
+        return retain_logs(node, keys.into_iter().map(|key| (key, None)).collect());
+    }
+
+    lin_kv::read(node, GROUPS_KEY.to_string(), move |node, msg| {
+        let groups = match msg.body {
+            Body::ReadOk(KvReadOkBody {
+                value: KvVal::Names(groups),
+                ..
+            }) => groups,
+            Body::Error(ErrorBody { code, .. }) if code == error::KEY_DOES_NOT_EXIST => {
+                BTreeSet::new()
+            }
+            body => {
+                eprintln!("failed to read the consumer groups: {:?}", body);
+                BTreeSet::new()
+            }
+        };
+        if groups.is_empty() {
+            return retain_logs(node, keys.iter().map(|key| (key.clone(), None)).collect());
+        }
+
+        let scan = Arc::new(Mutex::new(CommittedScan {
+            remaining: keys.len() * groups.len(),
+            lowest: HashMap::new(),
+            failed: false,
+        }));
+        for key in &keys {
+            for group in &groups {
+                let (scan, keys, key) = (scan.clone(), keys.clone(), key.clone());
+                lin_kv::read(node, committed_offset_key(group, &key), move |node, msg| {
+                    let mut scan = scan.lock().unwrap();
+                    match msg.body {
+                        Body::ReadOk(KvReadOkBody {
+                            value: KvVal::Num(offset),
+                            ..
+                        }) => {
+                            let lowest = scan.lowest.entry(key.clone()).or_insert(offset);
+                            *lowest = offset.min(*lowest);
+                        }
+                        // The group never committed on this key.
+                        Body::Error(ErrorBody { code, .. })
+                            if code == error::KEY_DOES_NOT_EXIST => {}
+                        _ => scan.failed = true,
+                    }
+                    scan.remaining -= 1;
+                    if scan.remaining > 0 {
+                        return;
+                    }
+
+                    let committed = keys
+                        .iter()
+                        .map(|key| {
+                            let lowest = scan.lowest.get(key).copied();
+                            (key.clone(), lowest.filter(|_| !scan.failed))
+                        })
+                        .collect();
+                    drop(scan);
+                    retain_logs(node, committed);
+                });
+            }
+        }
+    });
+}
+
+/// Cuts the logs this node still owns by retention, below the lowest offset
+/// committed on each if given, and compacts them. Followers are told where
+/// every log was cut each time, in case they missed it.
+fn retain_logs(node: &KafkaNode, committed: Vec<(Key, Option<Offset>)>) -> () {
+    let mut truncates = Vec::new();
+    {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let state = &mut *state;
+
+        for (key, committed) in committed {
+            if !owns(node, state, &key) {
+                continue;
+            }
+            match state.logs.retain(&key, committed) {
+                Ok(Some(start)) => {
+                    state.retained.insert(key.clone(), start);
+                }
+                Ok(None) => (),
+                Err(e) => eprintln!("failed to apply retention to {}: {}", key, e),
+            }
+            if state.compact {
+                if let Err(e) = state.logs.compact(&key, |value| *value) {
+                    eprintln!("failed to compact {}: {}", key, e);
+                }
+            }
+
+            if let Some(&before) = state.retained.get(&key) {
+                for follower in followers(node, state, &key) {
+                    truncates.push((follower.clone(), key.clone(), before));
+                }
+            }
+        }
+    }
+
+    for (follower, key, before) in truncates {
+        node.send_msg(&Message {
+            src: node.node_id().clone(),
+            dest: follower,
+            body: Body::Truncate { key, before },
+        });
+    }
+}
+
+/// Cuts this node's copy of a log where its owner's retention did.
+pub fn handle_truncate(node: &KafkaNode, msg: Message<Body>) -> () {
+    let (key, before) = match msg {
+        Message {
+            body: Body::Truncate { key, before },
+            ..
+        } => (key, before),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    if let Err(e) = state.logs.delete_before(&key, before) {
+        eprintln!("failed to truncate {} before {}: {}", key, before, e);
+    }
+}
+
+fn open_logs(node_id: &NodeId) -> LogStore<Val> {
+    let dir = match env::var(DATA_DIR_ENV) {
+        Ok(dir) => format!("{}/{}", dir, node_id),
+        Err(_) => {
+            let dir = env::temp_dir().join(format!("kafka-{}-{}", node_id, process::id()));
+            // Left behind by an earlier process with the same pid.
+            let _ = fs::remove_dir_all(&dir);
+            dir.to_string_lossy().into_owned()
+        }
+    };
+
+    let mut config = LogStoreConfig::default();
+    if let Some(bytes) = env_var(SEGMENT_BYTES_ENV) {
+        config.segment_bytes = bytes;
+    }
+    config.retention = env_var(RETENTION_MS_ENV).map(Duration::from_millis);
+    config.retention_bytes = env_var(RETENTION_BYTES_ENV);
+
+    LogStore::open(&dir, config).expect("failed to open the log store")
+}
+
+#[tokio::main]
+async fn main() {
+    let mut node: KafkaNode = Node::new();
+
+    node.add_handler("send".to_string(), handle_send);
+    node.add_handler("poll".to_string(), handle_poll);
+    node.add_handler("commit_offsets".to_string(), handle_commit_offsets);
+    node.add_handler(
+        "list_committed_offsets".to_string(),
+        handle_list_committed_offsets,
+    );
+    node.add_handler("send_batch".to_string(), handle_send_batch);
+    node.add_handler("prepare_batch".to_string(), handle_prepare_batch);
+    node.add_handler("resolve_batch".to_string(), handle_resolve_batch);
+    node.add_handler("replicate".to_string(), handle_replicate);
+    node.add_handler("catch_up".to_string(), handle_catch_up);
+    node.add_handler("heartbeat".to_string(), handle_heartbeat);
+    node.add_handler("truncate".to_string(), handle_truncate);
     node.add_handler("error".to_string(), handle_error);
 
     node.try_init();
 
-    let node = Arc::new(node);
+    let logs = open_logs(node.node_id());
+    // Recovered logs hold offsets the counters in lin-kv have already passed.
+    let latest_offsets = logs
+        .keys()
+        .filter_map(|key| Some((key.clone(), logs.next_offset(key)?)))
+        .collect();
+    let state = State {
+        logs,
+        producer_seqs: HashMap::new(),
+        committed_offsets: HashMap::new(),
+        groups: HashSet::new(),
+        retained: HashMap::new(),
+        latest_offsets,
+        reserved_offsets: HashMap::new(),
+        pending: HashMap::new(),
+        reserving: HashSet::new(),
+        reserve_retries: HashMap::new(),
+        commit_retries: HashMap::new(),
+        tails: HashMap::new(),
+        staged_txns: HashMap::new(),
+        delete_committed: env_var(DELETE_COMMITTED_ENV).unwrap_or(false),
+        compact: env_var(COMPACT_ENV).unwrap_or(false),
+        poll_limits: PollLimits::from_env(),
+        replicas: HashMap::new(),
+        awaiting_acks: HashMap::new(),
+        committing_txns: HashMap::new(),
+        hidden: HashMap::new(),
+        replication: Replication::from_env(node.node_ids().len()),
+        // Every node counts as alive until it has had time to say so.
+        last_heard: node
+            .node_ids()
+            .iter()
+            .map(|id| (id.clone(), Instant::now()))
+            .collect(),
+        synced: HashSet::new(),
+        catching_up: HashMap::new(),
+    };
+    let node = Arc::new(node.with_state(Mutex::new(state)));
 
     let main_node = node.clone();
     let main_jh = tokio::spawn(async move {
@@ -354,5 +2473,36 @@ async fn main() {
         }
     });
 
-    let _ = tokio::join!(main_jh);
+    let mut interval = time::interval(MAINTENANCE_INTERVAL);
+    let maintenance_node = node.clone();
+    let maintenance_jh = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            maintain_logs(&maintenance_node);
+            expire_txns(&maintenance_node);
+        }
+    });
+
+    let mut interval = time::interval(REPLICATION_INTERVAL);
+    let replication_node = node.clone();
+    let replication_jh = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            replicate_logs(&replication_node);
+            catch_up(&replication_node);
+            retry_reservations(&replication_node);
+            retry_commits(&replication_node);
+        }
+    });
+
+    let mut interval = time::interval(HEARTBEAT_INTERVAL);
+    let heartbeat_node = node.clone();
+    let heartbeat_jh = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            heartbeat(&heartbeat_node);
+        }
+    });
+
+    let _ = tokio::join!(main_jh, maintenance_jh, replication_jh, heartbeat_jh);
 }
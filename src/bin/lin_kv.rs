@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::time;
+
+use fly_dist_rs::{
+    messages::{
+        error::{self, ErrorBody},
+        kv::{KvCasBody, KvCasOkBody, KvReadBody, KvReadOkBody, KvWriteBody, KvWriteOkBody},
+        Message, MsgId,
+    },
+    node::{Node, NodeId},
+    raft::{self, LogIndex, Msg, ProposeError, Raft, RaftConfig, StateMachine, Term},
+};
+use serde::{Deserialize, Serialize};
+
+type Key = u64;
+type Val = i64;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Body {
+    Read(KvReadBody<Key>),
+    ReadOk(KvReadOkBody<Val>),
+    Write(KvWriteBody<Key, Val>),
+    WriteOk(KvWriteOkBody),
+    Cas(KvCasBody<Key, Val>),
+    CasOk(KvCasOkBody),
+
+    Raft(Msg<Store>),
+
+    Error(ErrorBody),
+}
+
+impl From<Msg<Store>> for Body {
+    fn from(msg: Msg<Store>) -> Self {
+        Body::Raft(msg)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    Read { key: Key },
+    Write { key: Key, value: Val },
+    Cas { key: Key, from: Val, to: Val },
+}
+
+/// The replicated key-value map. Reads go through the log as well, which
+/// keeps them linearizable without leader leases.
+#[derive(Default)]
+pub struct Store {
+    data: HashMap<Key, Val>,
+}
+
+impl StateMachine for Store {
+    type Command = Command;
+    type Output = Result<Option<Val>, (u32, String)>;
+    type Snapshot = HashMap<Key, Val>;
+
+    fn apply(self: &mut Self, command: &Command) -> Self::Output {
+        match *command {
+            Command::Read { key } => match self.data.get(&key) {
+                Some(value) => Ok(Some(*value)),
+                None => Err((error::KEY_DOES_NOT_EXIST, format!("key {} not found", key))),
+            },
+            Command::Write { key, value } => {
+                self.data.insert(key, value);
+                Ok(None)
+            }
+            Command::Cas { key, from, to } => match self.data.get_mut(&key) {
+                Some(value) if *value == from => {
+                    *value = to;
+                    Ok(None)
+                }
+                Some(value) => Err((
+                    error::PRECONDITION_FAILED,
+                    format!("expected {}, but had {}", from, value),
+                )),
+                None => Err((error::KEY_DOES_NOT_EXIST, format!("key {} not found", key))),
+            },
+        }
+    }
+
+    fn snapshot(self: &Self) -> Self::Snapshot {
+        self.data.clone()
+    }
+
+    fn restore(self: &mut Self, snapshot: Self::Snapshot) -> () {
+        self.data = snapshot;
+    }
+}
+
+/// A client request this node proposed, waiting for its log entry to apply.
+struct Pending {
+    term: Term,
+    client: NodeId,
+    msg_id: MsgId,
+    t: &'static str,
+}
+
+pub struct State
+where
+    Self: Send,
+{
+    raft: Raft<Store>,
+    pending: HashMap<LogIndex, Pending>,
+}
+
+type KvNode = Node<Mutex<State>, Body>;
+
+fn reply_body(in_reply_to: MsgId, output: <Store as StateMachine>::Output, command: &str) -> Body {
+    match (output, command) {
+        (Ok(Some(value)), _) => Body::ReadOk(KvReadOkBody { in_reply_to, value }),
+        (Ok(None), "cas") => Body::CasOk(KvCasOkBody { in_reply_to }),
+        (Ok(None), _) => Body::WriteOk(KvWriteOkBody { in_reply_to }),
+        (Err((code, text)), _) => Body::Error(ErrorBody {
+            in_reply_to,
+            code,
+            text,
+        }),
+    }
+}
+
+fn with_in_reply_to(body: Body, in_reply_to: MsgId) -> Body {
+    match body {
+        Body::ReadOk(KvReadOkBody { value, .. }) => {
+            Body::ReadOk(KvReadOkBody { in_reply_to, value })
+        }
+        Body::WriteOk(..) => Body::WriteOk(KvWriteOkBody { in_reply_to }),
+        Body::CasOk(..) => Body::CasOk(KvCasOkBody { in_reply_to }),
+        Body::Error(ErrorBody { code, text, .. }) => Body::Error(ErrorBody {
+            in_reply_to,
+            code,
+            text,
+        }),
+        _ => unreachable!(),
+    }
+}
+
+fn with_msg_id(body: Body, msg_id: MsgId) -> Body {
+    match body {
+        Body::Read(KvReadBody { key, .. }) => Body::Read(KvReadBody { msg_id, key }),
+        Body::Write(KvWriteBody { key, value, .. }) => {
+            Body::Write(KvWriteBody { msg_id, key, value })
+        }
+        Body::Cas(KvCasBody {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+            ..
+        }) => Body::Cas(KvCasBody {
+            msg_id,
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        }),
+        _ => unreachable!(),
+    }
+}
+
+/// Sends queued Raft messages and answers the clients whose entries were
+/// applied.
+fn drain(node: &KvNode, state: &mut State) -> () {
+    raft::flush(node, &mut state.raft);
+
+    for applied in state.raft.take_applied() {
+        let Some(pending) = state.pending.remove(&applied.index) else {
+            continue;
+        };
+
+        let body = match applied.output {
+            // Another leader's entry won this index, so ours never applied.
+            _ if applied.term != pending.term => Body::Error(ErrorBody {
+                in_reply_to: pending.msg_id,
+                code: error::ABORT,
+                text: "entry was superseded by a new leader".to_string(),
+            }),
+            Some(output) => reply_body(pending.msg_id, output, pending.t),
+            None => continue,
+        };
+
+        node.send_msg(&Message {
+            src: node.node_id().clone(),
+            dest: pending.client,
+            body,
+        });
+    }
+}
+
+pub fn handle_request(node: &KvNode, msg: Message<Body>) -> () {
+    let Message { src, dest, body } = msg;
+    let (msg_id, command, t) = match &body {
+        Body::Read(KvReadBody { msg_id, key }) => (*msg_id, Command::Read { key: *key }, "read"),
+        Body::Write(KvWriteBody { msg_id, key, value }) => (
+            *msg_id,
+            Command::Write {
+                key: *key,
+                value: *value,
+            },
+            "write",
+        ),
+        Body::Cas(KvCasBody {
+            msg_id,
+            key,
+            from,
+            to,
+            ..
+        }) => (
+            *msg_id,
+            Command::Cas {
+                key: *key,
+                from: *from,
+                to: *to,
+            },
+            "cas",
+        ),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    let leader = match state.raft.propose(command) {
+        Ok((index, term)) => {
+            state.pending.insert(
+                index,
+                Pending {
+                    term,
+                    client: src,
+                    msg_id,
+                    t,
+                },
+            );
+            return drain(node, &mut state);
+        }
+        Err(ProposeError::NotLeader(leader)) => leader,
+        Err(ProposeError::ConfigChangeInProgress) => unreachable!(),
+    };
+    drop(state);
+
+    // Requests are forwarded once; a forwarded request that misses the
+    // leader fails instead of bouncing between stale followers.
+    let forwarded = node.node_ids().contains(&src);
+    let Some(leader) = leader.filter(|_| !forwarded) else {
+        return node.send_msg(&Message {
+            src: dest,
+            dest: src,
+            body: Body::Error(ErrorBody {
+                in_reply_to: msg_id,
+                code: error::TEMPORARILY_UNAVAILABLE,
+                text: format!("no known leader for {}", t),
+            }),
+        });
+    };
+
+    let forward_msg = Message {
+        src: node.node_id().clone(),
+        dest: leader,
+        body: with_msg_id(body, node.next_msg_id()),
+    };
+    node.rpc_msg(&forward_msg, move |node, reply| {
+        node.send_msg(&Message {
+            src: dest.clone(),
+            dest: src.clone(),
+            body: with_in_reply_to(reply.body, msg_id),
+        })
+    });
+}
+
+pub fn handle_raft(node: &KvNode, msg: Message<Body>) -> () {
+    let (raft_msg, src) = match msg {
+        Message {
+            src,
+            body: Body::Raft(raft_msg),
+            ..
+        } => (raft_msg, src),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.raft.step(&src, raft_msg);
+    drain(node, &mut state);
+}
+
+fn tick(node: &KvNode) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.raft.tick(Instant::now());
+    drain(node, &mut state);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut node: KvNode = Node::new();
+
+    node.add_handler("read".to_string(), handle_request);
+    node.add_handler("write".to_string(), handle_request);
+    node.add_handler("cas".to_string(), handle_request);
+    node.add_handler("raft".to_string(), handle_raft);
+
+    node.try_init();
+
+    let raft = Raft::new(
+        node.node_id().clone(),
+        node.node_ids().clone(),
+        Store::default(),
+        RaftConfig::default(),
+    );
+    let node = Arc::new(node.with_state(Mutex::new(State {
+        raft,
+        pending: HashMap::new(),
+    })));
+
+    let main_node = Arc::clone(&node);
+    let main_task = tokio::spawn(async move {
+        loop {
+            main_node.one_loop();
+        }
+    });
+
+    let mut interval = time::interval(time::Duration::from_millis(10));
+    let tick_node = Arc::clone(&node);
+    let tick_task = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            tick(&tick_node);
+        }
+    });
+
+    let _ = tokio::join!(main_task, tick_task);
+}
@@ -0,0 +1,227 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::time;
+
+use fly_dist_rs::{
+    messages::{
+        broadcast::{BroadcastBody, BroadcastOkBody, JsonVal},
+        read::{ReadBody, ReadOkBody},
+        topology::{TopologyBody, TopologyOkBody},
+        Message,
+    },
+    node::Node,
+    propagate::PropagateVal,
+    total_order::{self, Mode, TotalOrderBroadcast, TotalOrderConfig, TotalOrderMsg},
+};
+use serde::{Deserialize, Serialize};
+
+/// `sequencer` (the default) or `consensus`.
+const MODE_ENV: &str = "TOTAL_ORDER_MODE";
+/// `int` (the default) or `json`.
+const VALUES_ENV: &str = "BROADCAST_VALUES";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[serde(bound(serialize = "Val: PropagateVal", deserialize = "Val: PropagateVal"))]
+pub enum Body<Val> {
+    Broadcast(BroadcastBody<Val>),
+    BroadcastOk(BroadcastOkBody),
+    Read(ReadBody),
+    ReadOk(ReadOkBody<Val>),
+    Topology(TopologyBody),
+    TopologyOk(TopologyOkBody),
+
+    TotalOrder(TotalOrderMsg<Val>),
+}
+
+impl<Val> From<TotalOrderMsg<Val>> for Body<Val> {
+    fn from(msg: TotalOrderMsg<Val>) -> Self {
+        Body::TotalOrder(msg)
+    }
+}
+
+pub struct State<Val: PropagateVal> {
+    pub total_order: TotalOrderBroadcast<Val>,
+    /// Every value delivered so far, in delivery order.
+    pub messages: Vec<Val>,
+}
+
+impl<Val: PropagateVal> State<Val> {
+    fn deliver(self: &mut Self) -> () {
+        let delivered = self.total_order.take_delivered();
+        self.messages
+            .extend(delivered.into_iter().map(|msg| msg.value));
+    }
+}
+
+type BroadcastNode<Val> = Node<Mutex<State<Val>>, Body<Val>>;
+
+pub fn handle_topology<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (TopologyBody { msg_id, .. }, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Topology(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    let body = Body::TopologyOk(TopologyOkBody {
+        in_reply_to: msg_id,
+        msg_id: node.next_msg_id(),
+    });
+
+    node.send_msg(&Message {
+        body,
+        src: dest,
+        dest: src,
+    })
+}
+
+pub fn handle_broadcast<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (
+        BroadcastBody {
+            msg_id,
+            message: value,
+        },
+        src,
+        dest,
+    ) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Broadcast(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.total_order.broadcast(value);
+    state.deliver();
+    total_order::flush(node, &mut state.total_order);
+
+    let body = Body::BroadcastOk(BroadcastOkBody {
+        in_reply_to: msg_id,
+    });
+
+    node.send_msg(&Message {
+        body,
+        src: dest,
+        dest: src,
+    })
+}
+
+pub fn handle_total_order<Val: PropagateVal>(
+    node: &BroadcastNode<Val>,
+    msg: Message<Body<Val>>,
+) -> () {
+    let (total_order_msg, src) = match msg {
+        Message {
+            src,
+            body: Body::TotalOrder(total_order_msg),
+            ..
+        } => (total_order_msg, src),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.total_order.step(&src, total_order_msg);
+    state.deliver();
+    total_order::flush(node, &mut state.total_order);
+}
+
+pub fn handle_read<Val: PropagateVal>(node: &BroadcastNode<Val>, msg: Message<Body<Val>>) -> () {
+    let (ReadBody { msg_id }, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Read(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    let state = node.state.as_ref().unwrap().lock().unwrap();
+
+    let body = Body::ReadOk(ReadOkBody {
+        in_reply_to: msg_id,
+        msg_id: node.next_msg_id(),
+        messages: state.messages.clone(),
+    });
+
+    node.send_msg(&Message {
+        body,
+        src: dest,
+        dest: src,
+    })
+}
+
+fn tick<Val: PropagateVal>(node: &BroadcastNode<Val>) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.total_order.tick(Instant::now());
+    state.deliver();
+    total_order::flush(node, &mut state.total_order);
+}
+
+#[tokio::main]
+async fn main() {
+    match env::var(VALUES_ENV).as_deref() {
+        Ok("int") | Err(_) => run::<i32>().await,
+        Ok("json") => run::<JsonVal>().await,
+        Ok(values) => panic!("unknown broadcast values {:?}", values),
+    }
+}
+
+async fn run<Val: PropagateVal>() {
+    let mode = match env::var(MODE_ENV).as_deref() {
+        Ok("sequencer") | Err(_) => Mode::Sequencer,
+        Ok("consensus") => Mode::Consensus,
+        Ok(mode) => panic!("unknown total order mode {:?}", mode),
+    };
+
+    let mut node: BroadcastNode<Val> = Node::new();
+
+    node.add_handler("topology".to_string(), handle_topology::<Val>);
+    node.add_handler("broadcast".to_string(), handle_broadcast::<Val>);
+    node.add_handler("read".to_string(), handle_read::<Val>);
+    node.add_handler("total_order".to_string(), handle_total_order::<Val>);
+
+    node.try_init();
+
+    let config = TotalOrderConfig {
+        mode,
+        ..TotalOrderConfig::default()
+    };
+    let total_order =
+        TotalOrderBroadcast::new(node.node_id().clone(), node.node_ids().clone(), config);
+    let node = Arc::new(node.with_state(Mutex::new(State {
+        total_order,
+        messages: Vec::new(),
+    })));
+
+    let main_node = Arc::clone(&node);
+    let main_task = tokio::spawn(async move {
+        loop {
+            main_node.one_loop();
+        }
+    });
+
+    let mut interval = time::interval(time::Duration::from_millis(10));
+    let tick_node = Arc::clone(&node);
+    let tick_task = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            tick(&tick_node);
+        }
+    });
+
+    let _ = tokio::join!(main_task, tick_task);
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use fly_dist_rs::{
+    messages::{
+        error::{self, ErrorBody},
+        kv::{KvCasBody, KvCasOkBody, KvReadBody, KvReadOkBody, KvWriteBody, KvWriteOkBody},
+        txn::{MicroOp, TxnBody, TxnKey, TxnOkBody},
+        Message, MsgId,
+    },
+    node::{Node, NodeId},
+};
+use serde::{Deserialize, Serialize};
+
+type Val = i64;
+type Op = MicroOp<Val, Vec<Val>>;
+type ThunkId = String;
+type TxnId = u64;
+
+/// Maps every list key to the immutable thunk holding its current value.
+type Root = BTreeMap<TxnKey, ThunkId>;
+
+const ROOT_KEY: &str = "root";
+const MAX_ATTEMPTS: u32 = 10;
+
+mod lin_kv {
+    use fly_dist_rs::messages::kv::LIN_KV;
+    use serde_json::Value;
+
+    use crate::*;
+
+    pub fn read<F>(node: &TxnNode, key: String, on_reply: F)
+    where
+        F: 'static + Fn(&TxnNode, Message<Body>) -> () + Send + Sync,
+    {
+        let msg = Message {
+            src: node.node_id().to_string(),
+            dest: LIN_KV.to_string(),
+            body: Body::Read(KvReadBody {
+                msg_id: node.next_msg_id(),
+                key,
+            }),
+        };
+
+        node.rpc_msg(&msg, on_reply);
+    }
+
+    pub fn write<F>(node: &TxnNode, key: String, value: Value, on_reply: F)
+    where
+        F: 'static + Fn(&TxnNode, Message<Body>) -> () + Send + Sync,
+    {
+        let msg = Message {
+            src: node.node_id().to_string(),
+            dest: LIN_KV.to_string(),
+            body: Body::Write(KvWriteBody {
+                msg_id: node.next_msg_id(),
+                key,
+                value,
+            }),
+        };
+
+        node.rpc_msg(&msg, on_reply);
+    }
+
+    pub fn cas<F>(node: &TxnNode, key: String, from: Value, to: Value, create: bool, on_reply: F)
+    where
+        F: 'static + Fn(&TxnNode, Message<Body>) -> () + Send + Sync,
+    {
+        let msg = Message {
+            src: node.node_id().to_string(),
+            dest: LIN_KV.to_string(),
+            body: Body::Cas(KvCasBody {
+                msg_id: node.next_msg_id(),
+                key,
+                from,
+                to,
+                create_if_not_exists: Some(create),
+            }),
+        };
+
+        node.rpc_msg(&msg, on_reply);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Body {
+    Txn(TxnBody<Val, Vec<Val>>),
+    TxnOk(TxnOkBody<Val, Vec<Val>>),
+
+    Read(KvReadBody),
+    ReadOk(KvReadOkBody<serde_json::Value>),
+    Write(KvWriteBody<String, serde_json::Value>),
+    WriteOk(KvWriteOkBody),
+    Cas(KvCasBody<String, serde_json::Value>),
+    CasOk(KvCasOkBody),
+
+    Error(ErrorBody),
+}
+
+/// A client transaction in flight. Each attempt reads the root, fetches the
+/// thunks of the touched keys, writes fresh thunks for the appended keys and
+/// finally swings the root over with a CAS.
+struct PendingTxn {
+    client: NodeId,
+    client_msg_id: MsgId,
+    txn: Vec<Op>,
+    attempts: u32,
+    root: Option<Root>,
+    new_root: Root,
+    result: Vec<Op>,
+    awaiting: usize,
+}
+
+pub struct State
+where
+    Self: Send,
+{
+    thunks: HashMap<ThunkId, Vec<Val>>,
+    next_thunk: u64,
+    next_txn: TxnId,
+    pending: HashMap<TxnId, PendingTxn>,
+}
+
+type TxnNode = Node<Mutex<State>, Body>;
+
+fn reply(node: &TxnNode, txn_id: TxnId, body: Body) -> () {
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    let Some(pending) = state.pending.remove(&txn_id) else {
+        return;
+    };
+    drop(state);
+
+    node.send_msg(&Message {
+        src: node.node_id().clone(),
+        dest: pending.client,
+        body,
+    })
+}
+
+fn abort(node: &TxnNode, txn_id: TxnId, code: u32, text: String) -> () {
+    let in_reply_to = {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        let Some(pending) = state.pending.get(&txn_id) else {
+            return;
+        };
+        pending.client_msg_id
+    };
+
+    reply(
+        node,
+        txn_id,
+        Body::Error(ErrorBody {
+            in_reply_to,
+            code,
+            text,
+        }),
+    )
+}
+
+fn read_root(node: &TxnNode, txn_id: TxnId) -> () {
+    lin_kv::read(node, ROOT_KEY.to_string(), move |node, msg| {
+        let root = match msg.body {
+            Body::ReadOk(KvReadOkBody { value, .. }) => match serde_json::from_value(value) {
+                Ok(root) => Some(root),
+                Err(e) => return abort(node, txn_id, error::CRASH, e.to_string()),
+            },
+            Body::Error(ErrorBody { code, .. }) if code == error::KEY_DOES_NOT_EXIST => None,
+            Body::Error(ErrorBody { code, text, .. }) => return abort(node, txn_id, code, text),
+            _ => unreachable!(),
+        };
+
+        {
+            let mut state = node.state.as_ref().unwrap().lock().unwrap();
+            let Some(pending) = state.pending.get_mut(&txn_id) else {
+                return;
+            };
+            pending.root = root;
+        }
+
+        fetch_thunks(node, txn_id);
+    });
+}
+
+fn fetch_thunks(node: &TxnNode, txn_id: TxnId) -> () {
+    let missing = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let State {
+            thunks, pending, ..
+        } = &mut *state;
+        let Some(pending) = pending.get_mut(&txn_id) else {
+            return;
+        };
+
+        let mut missing: Vec<ThunkId> = match &pending.root {
+            Some(root) => pending
+                .txn
+                .iter()
+                .filter_map(|op| root.get(&op.key()))
+                .filter(|&thunk_id| !thunks.contains_key(thunk_id))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        missing.sort();
+        missing.dedup();
+
+        pending.awaiting = missing.len();
+        missing
+    };
+
+    if missing.is_empty() {
+        return execute(node, txn_id);
+    }
+
+    for thunk_id in missing {
+        let id = thunk_id.clone();
+        lin_kv::read(node, thunk_id, move |node, msg| {
+            let list = match msg.body {
+                Body::ReadOk(KvReadOkBody { value, .. }) => match serde_json::from_value(value) {
+                    Ok(list) => list,
+                    Err(e) => return abort(node, txn_id, error::CRASH, e.to_string()),
+                },
+                Body::Error(ErrorBody { code, text, .. }) => {
+                    return abort(node, txn_id, code, text)
+                }
+                _ => unreachable!(),
+            };
+
+            let done = {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                state.thunks.insert(id.clone(), list);
+                let Some(pending) = state.pending.get_mut(&txn_id) else {
+                    return;
+                };
+                pending.awaiting -= 1;
+                pending.awaiting == 0
+            };
+
+            if done {
+                execute(node, txn_id);
+            }
+        });
+    }
+}
+
+fn execute(node: &TxnNode, txn_id: TxnId) -> () {
+    let writes = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let State {
+            thunks,
+            pending,
+            next_thunk,
+            ..
+        } = &mut *state;
+        let Some(pending) = pending.get_mut(&txn_id) else {
+            return;
+        };
+
+        let root = pending.root.clone().unwrap_or_default();
+        let mut lists: HashMap<TxnKey, Vec<Val>> = HashMap::new();
+        let mut appended = Vec::new();
+
+        pending.result = pending
+            .txn
+            .iter()
+            .map(|op| {
+                let key = op.key();
+                let list = lists.entry(key).or_insert_with(|| {
+                    root.get(&key)
+                        .and_then(|thunk_id| thunks.get(thunk_id))
+                        .cloned()
+                        .unwrap_or_default()
+                });
+
+                match op {
+                    MicroOp::Read(..) if list.is_empty() && !root.contains_key(&key) => {
+                        MicroOp::Read(key, None)
+                    }
+                    MicroOp::Read(..) => MicroOp::Read(key, Some(list.clone())),
+                    MicroOp::Append(_, value) => {
+                        list.push(*value);
+                        appended.push(key);
+                        op.clone()
+                    }
+                    MicroOp::Write(..) => op.clone(),
+                }
+            })
+            .collect();
+
+        appended.sort();
+        appended.dedup();
+
+        pending.new_root = root;
+        let mut writes = Vec::new();
+        for key in appended {
+            *next_thunk += 1;
+            let thunk_id = format!("{}-{}", node.node_id(), next_thunk);
+            let list = lists.remove(&key).unwrap();
+
+            thunks.insert(thunk_id.clone(), list.clone());
+            pending.new_root.insert(key, thunk_id.clone());
+            writes.push((thunk_id, list));
+        }
+        pending.awaiting = writes.len();
+
+        writes
+    };
+
+    // Read-only transactions linearize at the root read and need no CAS.
+    if writes.is_empty() {
+        return commit(node, txn_id);
+    }
+
+    for (thunk_id, list) in writes {
+        lin_kv::write(node, thunk_id, list.into(), move |node, msg| {
+            match msg.body {
+                Body::WriteOk(..) => (),
+                Body::Error(ErrorBody { code, text, .. }) => {
+                    return abort(node, txn_id, code, text)
+                }
+                _ => unreachable!(),
+            };
+
+            let done = {
+                let mut state = node.state.as_ref().unwrap().lock().unwrap();
+                let Some(pending) = state.pending.get_mut(&txn_id) else {
+                    return;
+                };
+                pending.awaiting -= 1;
+                pending.awaiting == 0
+            };
+
+            if done {
+                swap_root(node, txn_id);
+            }
+        });
+    }
+}
+
+fn swap_root(node: &TxnNode, txn_id: TxnId) -> () {
+    let (from, to, create) = {
+        let state = node.state.as_ref().unwrap().lock().unwrap();
+        let Some(pending) = state.pending.get(&txn_id) else {
+            return;
+        };
+
+        (
+            serde_json::to_value(pending.root.clone().unwrap_or_default()).unwrap(),
+            serde_json::to_value(&pending.new_root).unwrap(),
+            pending.root.is_none(),
+        )
+    };
+
+    lin_kv::cas(
+        node,
+        ROOT_KEY.to_string(),
+        from,
+        to,
+        create,
+        move |node, msg| match msg.body {
+            Body::CasOk(..) => commit(node, txn_id),
+            Body::Error(ErrorBody { code, .. }) if code == error::PRECONDITION_FAILED => {
+                retry(node, txn_id)
+            }
+            Body::Error(ErrorBody { code, text, .. }) => abort(node, txn_id, code, text),
+            _ => unreachable!(),
+        },
+    );
+}
+
+fn retry(node: &TxnNode, txn_id: TxnId) -> () {
+    let attempts = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let Some(pending) = state.pending.get_mut(&txn_id) else {
+            return;
+        };
+        pending.attempts += 1;
+        pending.attempts
+    };
+
+    if attempts >= MAX_ATTEMPTS {
+        return abort(
+            node,
+            txn_id,
+            error::TXN_CONFLICT,
+            "root changed concurrently".to_string(),
+        );
+    }
+
+    read_root(node, txn_id);
+}
+
+fn commit(node: &TxnNode, txn_id: TxnId) -> () {
+    let body = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        let Some(pending) = state.pending.get_mut(&txn_id) else {
+            return;
+        };
+
+        Body::TxnOk(TxnOkBody {
+            in_reply_to: pending.client_msg_id,
+            txn: std::mem::take(&mut pending.result),
+        })
+    };
+
+    reply(node, txn_id, body)
+}
+
+pub fn handle_txn(node: &TxnNode, msg: Message<Body>) -> () {
+    let (TxnBody { msg_id, txn }, src) = match msg {
+        Message {
+            src,
+            body: Body::Txn(body),
+            ..
+        } => (body, src),
+        _ => unreachable!(),
+    };
+
+    let txn_id = {
+        let mut state = node.state.as_ref().unwrap().lock().unwrap();
+        state.next_txn += 1;
+        let txn_id = state.next_txn;
+
+        state.pending.insert(
+            txn_id,
+            PendingTxn {
+                client: src,
+                client_msg_id: msg_id,
+                txn,
+                attempts: 0,
+                root: None,
+                new_root: Root::new(),
+                result: Vec::new(),
+                awaiting: 0,
+            },
+        );
+
+        txn_id
+    };
+
+    read_root(node, txn_id);
+}
+
+#[tokio::main]
+async fn main() {
+    let state = State {
+        thunks: HashMap::new(),
+        next_thunk: 0,
+        next_txn: 0,
+        pending: HashMap::new(),
+    };
+    let mut node = Node::new().with_state(Mutex::new(state));
+
+    node.add_handler("txn".to_string(), handle_txn);
+
+    node.try_init();
+
+    let node = Arc::new(node);
+
+    let main_node = node.clone();
+    let main_jh = tokio::spawn(async move {
+        loop {
+            main_node.one_loop();
+        }
+    });
+
+    let _ = tokio::join!(main_jh);
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::time;
+
+use fly_dist_rs::{
+    messages::{
+        txn::{MicroOp, TxnBody, TxnKey, TxnOkBody},
+        Message, MsgId,
+    },
+    node::{Node, NodeId},
+};
+use serde::{Deserialize, Serialize};
+
+type Val = i64;
+
+/// Lamport timestamp of the transaction that wrote a value, tie-broken by the
+/// id of the node that executed it. Every write of a transaction carries the
+/// same version, so replicas order whole write sets consistently.
+type Version = (u64, NodeId);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Body {
+    Txn(TxnBody<Val>),
+    TxnOk(TxnOkBody<Val>),
+    Replicate {
+        msg_id: MsgId,
+        version: Version,
+        writes: Vec<(TxnKey, Val)>,
+    },
+    ReplicateOk {
+        in_reply_to: MsgId,
+    },
+}
+
+pub struct State
+where
+    Self: Send,
+{
+    registers: HashMap<TxnKey, (Val, Version)>,
+    clock: u64,
+    unconfirmed_msgs: HashMap<MsgId, Message<Body>>,
+}
+
+type TxnNode = Node<Mutex<State>, Body>;
+
+impl State {
+    fn apply_writes(self: &mut Self, writes: Vec<(TxnKey, Val)>, version: &Version) -> () {
+        for (key, value) in writes {
+            match self.registers.get(&key) {
+                Some((_, current)) if current > version => continue,
+                _ => self.registers.insert(key, (value, version.clone())),
+            };
+        }
+    }
+}
+
+pub fn handle_txn(node: &TxnNode, msg: Message<Body>) -> () {
+    let (TxnBody { msg_id, txn }, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body: Body::Txn(body),
+        } => (body, src, dest),
+        _ => unreachable!(),
+    };
+
+    // The whole transaction runs under the state lock, so no other local
+    // transaction or replicated write set can interleave with it.
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.clock += 1;
+    let version = (state.clock, node.node_id().clone());
+
+    let mut writes = Vec::new();
+    let txn = txn
+        .into_iter()
+        .map(|op| match op {
+            MicroOp::Read(key, _) => {
+                let value = writes
+                    .iter()
+                    .rev()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| *v)
+                    .or_else(|| state.registers.get(&key).map(|(v, _)| *v));
+                MicroOp::Read(key, value)
+            }
+            MicroOp::Write(key, value) => {
+                writes.push((key, value));
+                MicroOp::Write(key, value)
+            }
+            op => op,
+        })
+        .collect();
+
+    state.apply_writes(writes.clone(), &version);
+
+    if !writes.is_empty() {
+        for peer in node.node_ids().iter().filter(|&id| id != node.node_id()) {
+            let replicate_msg_id = node.next_msg_id();
+            let msg = Message {
+                src: node.node_id().clone(),
+                dest: peer.clone(),
+                body: Body::Replicate {
+                    msg_id: replicate_msg_id,
+                    version: version.clone(),
+                    writes: writes.clone(),
+                },
+            };
+            node.send_msg(&msg);
+            state.unconfirmed_msgs.insert(replicate_msg_id, msg);
+        }
+    }
+    drop(state);
+
+    node.send_msg(&Message {
+        src: dest,
+        dest: src,
+        body: Body::TxnOk(TxnOkBody {
+            in_reply_to: msg_id,
+            txn,
+        }),
+    })
+}
+
+pub fn handle_replicate(node: &TxnNode, msg: Message<Body>) -> () {
+    let (msg_id, version, writes, src, dest) = match msg {
+        Message {
+            src,
+            dest,
+            body:
+                Body::Replicate {
+                    msg_id,
+                    version,
+                    writes,
+                },
+        } => (msg_id, version, writes, src, dest),
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.clock = state.clock.max(version.0);
+    state.apply_writes(writes, &version);
+    drop(state);
+
+    node.send_msg(&Message {
+        src: dest,
+        dest: src,
+        body: Body::ReplicateOk {
+            in_reply_to: msg_id,
+        },
+    })
+}
+
+pub fn handle_replicate_ok(node: &TxnNode, msg: Message<Body>) -> () {
+    let in_reply_to = match msg {
+        Message {
+            body: Body::ReplicateOk { in_reply_to },
+            ..
+        } => in_reply_to,
+        _ => unreachable!(),
+    };
+
+    let mut state = node.state.as_ref().unwrap().lock().unwrap();
+    state.unconfirmed_msgs.remove(&in_reply_to);
+}
+
+fn resend_un_resp_msgs(node: &TxnNode) -> () {
+    let state = node.state.as_ref().unwrap().lock().unwrap();
+
+    for msg in state.unconfirmed_msgs.values() {
+        node.send_msg(msg);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let state = State {
+        registers: HashMap::new(),
+        clock: 0,
+        unconfirmed_msgs: HashMap::new(),
+    };
+    let mut node = Node::new().with_state(Mutex::new(state));
+
+    node.add_handler("txn".to_string(), handle_txn);
+    node.add_handler("replicate".to_string(), handle_replicate);
+    node.add_handler("replicate_ok".to_string(), handle_replicate_ok);
+
+    node.try_init();
+
+    let node = Arc::new(node);
+
+    let main_node = Arc::clone(&node);
+    let main_task = tokio::spawn(async move {
+        loop {
+            main_node.one_loop();
+        }
+    });
+
+    let mut interval = time::interval(time::Duration::from_millis(500));
+    let resend_node = Arc::clone(&node);
+    let resend_task = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            resend_un_resp_msgs(&resend_node);
+        }
+    });
+
+    let _ = tokio::join!(main_task, resend_task);
+}
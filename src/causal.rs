@@ -0,0 +1,232 @@
+use std::{collections::HashSet, fmt::Debug, time::Instant};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use crate::messages::causal::{CausalMsg, VectorClock};
+use crate::{
+    node::{Node, NodeId},
+    propagate::{self, PropagateConfig, PropagateMsg, PropagateVal, Propagator},
+};
+
+/// Broadcasts values to every node and delivers them in causal order: a
+/// value is only delivered once everything its origin had delivered before
+/// broadcasting it has been delivered here too. Dissemination is left to a
+/// `Propagator`; this only holds values back until they are deliverable.
+/// Like the `Propagator` it does no I/O of its own.
+pub struct CausalBroadcast<Val> {
+    id: NodeId,
+    propagator: Propagator<CausalMsg<Val>>,
+    /// How many messages from each origin have been delivered here.
+    clock: VectorClock,
+    /// Messages that arrived before some message they depend on.
+    held_back: Vec<CausalMsg<Val>>,
+    /// Delivered values, not yet taken by the host.
+    delivered: Vec<CausalMsg<Val>>,
+}
+
+impl<Val> CausalBroadcast<Val>
+where
+    Val: PropagateVal,
+{
+    pub fn new(id: NodeId, node_ids: Vec<NodeId>, config: PropagateConfig) -> Self {
+        let config = PropagateConfig {
+            collect_new_vals: true,
+            ..config
+        };
+
+        CausalBroadcast {
+            propagator: Propagator::new(id.clone(), node_ids, config),
+            id,
+            clock: VectorClock::new(),
+            held_back: Vec::new(),
+            delivered: Vec::new(),
+        }
+    }
+
+    pub fn clock(self: &Self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn set_neighbours(self: &mut Self, neighbours: Vec<NodeId>) -> () {
+        self.propagator.set_neighbours(neighbours);
+    }
+
+    /// Broadcasts `value`, which is delivered here right away.
+    pub fn broadcast(self: &mut Self, value: Val) -> () {
+        let mut clock = self.clock.clone();
+        *clock.entry(self.id.clone()).or_default() += 1;
+        let msg = CausalMsg {
+            origin: self.id.clone(),
+            clock,
+            value,
+        };
+
+        self.propagator.insert(HashSet::from([msg]));
+        self.receive();
+    }
+
+    pub fn step(self: &mut Self, from: &NodeId, msg: PropagateMsg) -> () {
+        self.propagator.step(from, msg);
+        self.receive();
+    }
+
+    pub fn tick(self: &mut Self, now: Instant) -> () {
+        self.propagator.tick(now);
+        self.receive();
+    }
+
+    pub fn take_outbox(self: &mut Self) -> Vec<(NodeId, PropagateMsg)> {
+        self.propagator.take_outbox()
+    }
+
+    /// Messages delivered since the last call, in causal order.
+    pub fn take_delivered(self: &mut Self) -> Vec<CausalMsg<Val>> {
+        std::mem::take(&mut self.delivered)
+    }
+
+    fn seen(self: &Self, origin: &NodeId) -> u64 {
+        self.clock.get(origin).copied().unwrap_or(0)
+    }
+
+    /// Whether `msg` is the next one from its origin, and everything it
+    /// depends on from other origins has been delivered.
+    fn deliverable(self: &Self, msg: &CausalMsg<Val>) -> bool {
+        let next = self.seen(&msg.origin) + 1;
+        msg.clock.get(&msg.origin) == Some(&next)
+            && msg
+                .clock
+                .iter()
+                .all(|(node, &count)| node == &msg.origin || count <= self.seen(node))
+    }
+
+    fn receive(self: &mut Self) -> () {
+        let new_msgs = self.propagator.take_new_vals();
+        if new_msgs.is_empty() {
+            return;
+        }
+        self.held_back.extend(new_msgs);
+
+        while let Some(i) = self.held_back.iter().position(|msg| self.deliverable(msg)) {
+            let msg = self.held_back.swap_remove(i);
+            *self.clock.entry(msg.origin.clone()).or_default() += 1;
+            self.delivered.push(msg);
+        }
+    }
+}
+
+/// Sends everything queued by `causal` through `node`.
+pub fn flush<S, B, Val>(node: &Node<S, B>, causal: &mut CausalBroadcast<Val>) -> ()
+where
+    S: Send,
+    B: Serialize + DeserializeOwned + Send + Clone + Debug + From<PropagateMsg>,
+    Val: PropagateVal,
+{
+    propagate::flush(node, &mut causal.propagator);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact::{NodeBitmap, ValueSet};
+
+    fn ids() -> Vec<NodeId> {
+        (0..3).map(|i| format!("n{}", i)).collect()
+    }
+
+    fn node(id: &str) -> CausalBroadcast<u32> {
+        let mut node = CausalBroadcast::new(id.to_string(), ids(), PropagateConfig::default());
+        node.set_neighbours(ids().into_iter().filter(|n| n != id).collect());
+        node
+    }
+
+    /// Broadcasts `value` from `node` and returns the message carrying it.
+    fn broadcast(node: &mut CausalBroadcast<u32>, value: u32) -> CausalMsg<u32> {
+        node.broadcast(value);
+        node.take_delivered().pop().unwrap()
+    }
+
+    /// Pushes `msgs` to `node` as if they came from `from`, and returns the
+    /// values it delivered.
+    fn receive(node: &mut CausalBroadcast<u32>, from: &str, msgs: &[&CausalMsg<u32>]) -> Vec<u32> {
+        let push = PropagateMsg::Push {
+            id: 0,
+            values: ValueSet::encode(msgs.iter().copied()),
+            known_nodes: NodeBitmap::encode(&[], &ids()),
+        };
+        node.step(&from.to_string(), push);
+        node.take_delivered().into_iter().map(|m| m.value).collect()
+    }
+
+    #[test]
+    fn broadcasts_are_delivered_here_right_away() {
+        let mut n0 = node("n0");
+        let a = broadcast(&mut n0, 1);
+        let b = broadcast(&mut n0, 2);
+
+        assert_eq!(a.clock, VectorClock::from([("n0".to_string(), 1)]));
+        assert_eq!(b.clock, VectorClock::from([("n0".to_string(), 2)]));
+        assert_eq!(n0.clock(), &b.clock);
+    }
+
+    #[test]
+    fn holds_back_a_chain_until_its_first_message_arrives() {
+        let mut n0 = node("n0");
+        let msgs: Vec<CausalMsg<u32>> = (1..=4).map(|v| broadcast(&mut n0, v)).collect();
+
+        let mut n1 = node("n1");
+        for msg in msgs[1..].iter().rev() {
+            assert_eq!(receive(&mut n1, "n0", &[msg]), Vec::<u32>::new());
+        }
+        assert_eq!(receive(&mut n1, "n0", &[&msgs[0]]), vec![1, 2, 3, 4]);
+        assert_eq!(n1.clock(), &msgs[3].clock);
+    }
+
+    #[test]
+    fn waits_for_what_the_origin_had_delivered() {
+        let (mut n0, mut n1, mut n2) = (node("n0"), node("n1"), node("n2"));
+        let a = broadcast(&mut n0, 1);
+        assert_eq!(receive(&mut n1, "n0", &[&a]), vec![1]);
+        let b = broadcast(&mut n1, 2);
+        let c = broadcast(&mut n0, 3);
+
+        // b depends on a from another origin, c on a from its own.
+        assert_eq!(receive(&mut n2, "n1", &[&b]), Vec::<u32>::new());
+        assert_eq!(receive(&mut n2, "n0", &[&c]), Vec::<u32>::new());
+        let delivered = receive(&mut n2, "n0", &[&a]);
+        assert_eq!(delivered[0], 1);
+        assert_eq!(delivered.len(), 3);
+        assert!(delivered.contains(&2) && delivered.contains(&3));
+        assert_eq!(
+            n2.clock(),
+            &VectorClock::from([("n0".to_string(), 2), ("n1".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn delivers_concurrent_messages_as_they_arrive() {
+        let (mut n0, mut n1, mut n2) = (node("n0"), node("n1"), node("n2"));
+        let x = broadcast(&mut n0, 1);
+        let y = broadcast(&mut n1, 2);
+
+        assert_eq!(receive(&mut n2, "n1", &[&y]), vec![2]);
+        assert_eq!(receive(&mut n2, "n0", &[&x]), vec![1]);
+        assert_eq!(receive(&mut n0, "n1", &[&y]), vec![2]);
+        assert_eq!(receive(&mut n1, "n0", &[&x]), vec![1]);
+    }
+
+    #[test]
+    fn delivers_duplicates_once() {
+        let (mut n0, mut n1, mut n2) = (node("n0"), node("n1"), node("n2"));
+        let a = broadcast(&mut n0, 1);
+        let b = broadcast(&mut n0, 2);
+
+        assert_eq!(receive(&mut n1, "n0", &[&b]), Vec::<u32>::new());
+        assert_eq!(receive(&mut n1, "n2", &[&b]), Vec::<u32>::new());
+        assert_eq!(receive(&mut n1, "n0", &[&a, &b]), vec![1, 2]);
+        assert_eq!(receive(&mut n1, "n2", &[&a, &b]), Vec::<u32>::new());
+        assert_eq!(receive(&mut n0, "n1", &[&a]), Vec::<u32>::new());
+
+        assert_eq!(receive(&mut n2, "n0", &[&a, &a]), vec![1]);
+        assert_eq!(n1.clock(), &b.clock);
+    }
+}
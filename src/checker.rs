@@ -0,0 +1,889 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Debug, Display},
+    hash::Hash,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::messages::txn::{MicroOp, TxnKey};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpType {
+    /// An operation as it was invoked, before its outcome was known. Its
+    /// completion carries what it did, so invocations are skipped.
+    Invoke,
+    Ok,
+    Fail,
+    Info,
+}
+
+/// One completed operation of a recorded `txn` history, shaped like the
+/// entries of a Jepsen history: `value` holds the transaction as the client
+/// saw it completed, so reads carry the values they observed. Raw histories
+/// that also hold the invocations can be checked as they are.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound(
+    serialize = "Val: Serialize, ReadVal: Serialize",
+    deserialize = "Val: DeserializeOwned, ReadVal: DeserializeOwned"
+))]
+pub struct HistoryOp<Val = i64, ReadVal = Val> {
+    #[serde(rename = "type")]
+    pub t: OpType,
+    pub process: u64,
+    pub value: Vec<MicroOp<Val, ReadVal>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DepKind {
+    WW,
+    WR,
+    RW,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub from: usize,
+    pub to: usize,
+    pub kind: DepKind,
+    pub key: TxnKey,
+    pub explanation: String,
+}
+
+/// A dependency cycle between transactions, identified by their index in
+/// the checked history.
+#[derive(Debug, Clone)]
+pub struct Cycle {
+    pub steps: Vec<Dependency>,
+    pub txns: HashMap<usize, String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Anomaly {
+    G0(Cycle),
+    G1a {
+        reader: usize,
+        writer: usize,
+        key: TxnKey,
+        value: String,
+    },
+    G1b {
+        reader: usize,
+        writer: usize,
+        key: TxnKey,
+        value: String,
+    },
+    G1c(Cycle),
+    GSingle(Cycle),
+    G2(Cycle),
+    IncompatibleOrder {
+        key: TxnKey,
+        values: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    SnapshotIsolation,
+    Serializable,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Anomaly {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            Anomaly::G0(_) => "G0",
+            Anomaly::G1a { .. } => "G1a",
+            Anomaly::G1b { .. } => "G1b",
+            Anomaly::G1c(_) => "G1c",
+            Anomaly::GSingle(_) => "G-single",
+            Anomaly::G2(_) => "G2",
+            Anomaly::IncompatibleOrder { .. } => "incompatible-order",
+        }
+    }
+
+    fn prohibited_by(self: &Self, level: IsolationLevel) -> bool {
+        match (self, level) {
+            (Anomaly::G0(_), _) => true,
+            (_, IsolationLevel::ReadUncommitted) => false,
+            (Anomaly::G1a { .. } | Anomaly::G1b { .. } | Anomaly::G1c(_), _) => true,
+            (Anomaly::IncompatibleOrder { .. }, _) => true,
+            (_, IsolationLevel::ReadCommitted) => false,
+            (Anomaly::GSingle(_), _) => true,
+            (Anomaly::G2(_), IsolationLevel::SnapshotIsolation) => false,
+            (Anomaly::G2(_), IsolationLevel::Serializable) => true,
+        }
+    }
+}
+
+impl Report {
+    pub fn is_valid(self: &Self, level: IsolationLevel) -> bool {
+        !self.anomalies.iter().any(|a| a.prohibited_by(level))
+    }
+
+    pub fn anomaly_types(self: &Self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.anomalies.iter().map(|a| a.name()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Display for DepKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DepKind::WW => write!(f, "ww"),
+            DepKind::WR => write!(f, "wr"),
+            DepKind::RW => write!(f, "rw"),
+        }
+    }
+}
+
+impl Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Let:")?;
+        for step in &self.steps {
+            writeln!(f, "  T{} = {}", step.from, self.txns[&step.from])?;
+        }
+        writeln!(f, "Then:")?;
+        for step in &self.steps {
+            writeln!(
+                f,
+                "  - T{} < T{} ({} on key {}), because {}.",
+                step.from, step.to, step.kind, step.key, step.explanation
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::G0(cycle)
+            | Anomaly::G1c(cycle)
+            | Anomaly::GSingle(cycle)
+            | Anomaly::G2(cycle) => write!(f, "{}:\n{}", self.name(), cycle),
+            Anomaly::G1a {
+                reader,
+                writer,
+                key,
+                value,
+            } => write!(
+                f,
+                "G1a: T{} read {} on key {}, written by failed T{}",
+                reader, value, key, writer
+            ),
+            Anomaly::G1b {
+                reader,
+                writer,
+                key,
+                value,
+            } => write!(
+                f,
+                "G1b: T{} read {} on key {}, an intermediate write of T{}",
+                reader, value, key, writer
+            ),
+            Anomaly::IncompatibleOrder { key, values } => write!(
+                f,
+                "incompatible-order: reads of key {} disagree: {}",
+                key,
+                values.join(" vs ")
+            ),
+        }
+    }
+}
+
+struct Graph {
+    n: usize,
+    edges: Vec<Dependency>,
+    adj: Vec<Vec<usize>>,
+    seen: HashSet<(usize, usize, DepKind)>,
+}
+
+impl Graph {
+    fn new(n: usize) -> Self {
+        Graph {
+            n,
+            edges: Vec::new(),
+            adj: vec![Vec::new(); n],
+            seen: HashSet::new(),
+        }
+    }
+
+    fn link(
+        self: &mut Self,
+        from: usize,
+        to: usize,
+        kind: DepKind,
+        key: TxnKey,
+        explanation: String,
+    ) {
+        if from == to || !self.seen.insert((from, to, kind)) {
+            return;
+        }
+
+        self.adj[from].push(self.edges.len());
+        self.edges.push(Dependency {
+            from,
+            to,
+            kind,
+            key,
+            explanation,
+        });
+    }
+
+    /// Strongly connected components (Kosaraju) over the edges of the given
+    /// kinds, keeping only components that can hold a cycle.
+    fn sccs(self: &Self, kinds: &[DepKind]) -> Vec<Vec<usize>> {
+        let allowed = |e: &usize| kinds.contains(&self.edges[*e].kind);
+
+        let mut order = Vec::with_capacity(self.n);
+        let mut visited = vec![false; self.n];
+        for start in 0..self.n {
+            if visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            let mut stack = vec![(start, 0)];
+            while let Some((v, i)) = stack.pop() {
+                let next = self.adj[v][i..]
+                    .iter()
+                    .enumerate()
+                    .find(|(_, e)| allowed(e) && !visited[self.edges[**e].to]);
+                match next {
+                    Some((j, e)) => {
+                        let to = self.edges[*e].to;
+                        visited[to] = true;
+                        stack.push((v, i + j + 1));
+                        stack.push((to, 0));
+                    }
+                    None => order.push(v),
+                }
+            }
+        }
+
+        let mut radj = vec![Vec::new(); self.n];
+        for e in self.edges.iter().filter(|e| kinds.contains(&e.kind)) {
+            radj[e.to].push(e.from);
+        }
+
+        let mut component = vec![usize::MAX; self.n];
+        let mut sccs = Vec::new();
+        for &start in order.iter().rev() {
+            if component[start] != usize::MAX {
+                continue;
+            }
+            let id = sccs.len();
+            let mut members = vec![start];
+            let mut stack = vec![start];
+            component[start] = id;
+            while let Some(v) = stack.pop() {
+                for &u in &radj[v] {
+                    if component[u] == usize::MAX {
+                        component[u] = id;
+                        members.push(u);
+                        stack.push(u);
+                    }
+                }
+            }
+            sccs.push(members);
+        }
+
+        sccs.into_iter().filter(|scc| scc.len() > 1).collect()
+    }
+
+    /// Shortest path of edge indices from `from` to `to`, staying inside
+    /// `within` and only following edges of the given kinds.
+    fn path(
+        self: &Self,
+        from: usize,
+        to: usize,
+        kinds: &[DepKind],
+        within: &HashSet<usize>,
+    ) -> Option<Vec<usize>> {
+        let mut via: HashMap<usize, usize> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        let mut visited = HashSet::from([from]);
+
+        while let Some(v) = queue.pop_front() {
+            if v == to {
+                let mut path = Vec::new();
+                let mut cur = to;
+                while cur != from {
+                    let e = via[&cur];
+                    path.push(e);
+                    cur = self.edges[e].from;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &e in &self.adj[v] {
+                let edge = &self.edges[e];
+                if kinds.contains(&edge.kind)
+                    && within.contains(&edge.to)
+                    && visited.insert(edge.to)
+                {
+                    via.insert(edge.to, e);
+                    queue.push_back(edge.to);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Looks for a cycle that starts with one of the `first` edges and closes
+    /// through edges of `rest` kinds, accepted only if `accept` holds.
+    fn find_cycle<F>(
+        self: &Self,
+        scc: &[usize],
+        first: DepKind,
+        rest: &[DepKind],
+        accept: F,
+    ) -> Option<Vec<usize>>
+    where
+        F: Fn(&[usize]) -> bool,
+    {
+        let within: HashSet<usize> = scc.iter().cloned().collect();
+
+        for &v in scc {
+            for &e in &self.adj[v] {
+                let edge = &self.edges[e];
+                if edge.kind != first || !within.contains(&edge.to) {
+                    continue;
+                }
+                let Some(mut path) = self.path(edge.to, edge.from, rest, &within) else {
+                    continue;
+                };
+                path.insert(0, e);
+                if accept(&path) {
+                    return Some(path);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn cycle<Val, ReadVal>(
+        self: &Self,
+        path: Vec<usize>,
+        history: &[HistoryOp<Val, ReadVal>],
+    ) -> Cycle
+    where
+        Val: Serialize,
+        ReadVal: Serialize,
+    {
+        let steps: Vec<Dependency> = path.into_iter().map(|e| self.edges[e].clone()).collect();
+        let txns = steps
+            .iter()
+            .map(|s| {
+                (
+                    s.from,
+                    serde_json::to_string(&history[s.from].value).unwrap(),
+                )
+            })
+            .collect();
+
+        Cycle { steps, txns }
+    }
+
+    fn cycle_anomalies<Val, ReadVal>(
+        self: &Self,
+        history: &[HistoryOp<Val, ReadVal>],
+    ) -> Vec<Anomaly>
+    where
+        Val: Serialize,
+        ReadVal: Serialize,
+    {
+        use DepKind::*;
+
+        let rw_count = |path: &[usize]| path.iter().filter(|&&e| self.edges[e].kind == RW).count();
+        let mut anomalies = Vec::new();
+
+        for scc in self.sccs(&[WW]) {
+            if let Some(path) = self.find_cycle(&scc, WW, &[WW], |_| true) {
+                anomalies.push(Anomaly::G0(self.cycle(path, history)));
+            }
+        }
+
+        for scc in self.sccs(&[WW, WR]) {
+            if let Some(path) = self.find_cycle(&scc, WR, &[WW, WR], |_| true) {
+                anomalies.push(Anomaly::G1c(self.cycle(path, history)));
+            }
+        }
+
+        for scc in self.sccs(&[WW, WR, RW]) {
+            if let Some(path) = self.find_cycle(&scc, RW, &[WW, WR], |_| true) {
+                anomalies.push(Anomaly::GSingle(self.cycle(path, history)));
+            } else if let Some(path) = self.find_cycle(&scc, RW, &[WW, WR, RW], |p| rw_count(p) > 1)
+            {
+                anomalies.push(Anomaly::G2(self.cycle(path, history)));
+            }
+        }
+
+        anomalies
+    }
+}
+
+/// Writes of committed or indeterminate transactions, keyed by what they
+/// wrote. Failed transactions are tracked separately for G1a.
+struct Writes<Val> {
+    writer: HashMap<(TxnKey, Val), usize>,
+    failed: HashMap<(TxnKey, Val), usize>,
+    /// Writes that a transaction later overwrote on the same key.
+    intermediate: HashSet<(TxnKey, Val)>,
+}
+
+impl<Val> Writes<Val>
+where
+    Val: Copy + Eq + Hash,
+{
+    fn index<ReadVal>(history: &[HistoryOp<Val, ReadVal>]) -> Self {
+        let mut writes = Writes {
+            writer: HashMap::new(),
+            failed: HashMap::new(),
+            intermediate: HashSet::new(),
+        };
+
+        for (i, op) in history.iter().enumerate() {
+            if op.t == OpType::Invoke {
+                continue;
+            }
+            let mut last: HashMap<TxnKey, Val> = HashMap::new();
+            for micro_op in &op.value {
+                let (MicroOp::Write(key, value) | MicroOp::Append(key, value)) = micro_op else {
+                    continue;
+                };
+                if let Some(prev) = last.insert(*key, *value) {
+                    writes.intermediate.insert((*key, prev));
+                }
+                match op.t {
+                    OpType::Fail => writes.failed.insert((*key, *value), i),
+                    _ => writes.writer.insert((*key, *value), i),
+                };
+            }
+        }
+
+        writes
+    }
+
+    fn check_read(
+        self: &Self,
+        reader: usize,
+        key: TxnKey,
+        value: Val,
+        observed_last: bool,
+        anomalies: &mut Vec<Anomaly>,
+    ) -> Option<usize>
+    where
+        Val: Debug,
+    {
+        if let Some(&writer) = self.failed.get(&(key, value)) {
+            anomalies.push(Anomaly::G1a {
+                reader,
+                writer,
+                key,
+                value: format!("{:?}", value),
+            });
+            return None;
+        }
+
+        let writer = *self.writer.get(&(key, value))?;
+        if observed_last && writer != reader && self.intermediate.contains(&(key, value)) {
+            anomalies.push(Anomaly::G1b {
+                reader,
+                writer,
+                key,
+                value: format!("{:?}", value),
+            });
+        }
+
+        Some(writer)
+    }
+}
+
+/// Reads a transaction made before writing the key itself, i.e. the ones
+/// that observe other transactions' state.
+fn external_reads<Val, ReadVal>(op: &HistoryOp<Val, ReadVal>) -> Vec<(TxnKey, &Option<ReadVal>)> {
+    let mut written = HashSet::new();
+    let mut reads = Vec::new();
+
+    for micro_op in &op.value {
+        match micro_op {
+            MicroOp::Read(key, value) if !written.contains(key) => reads.push((*key, value)),
+            MicroOp::Read(..) => (),
+            MicroOp::Write(key, _) | MicroOp::Append(key, _) => {
+                written.insert(*key);
+            }
+        }
+    }
+
+    reads
+}
+
+/// Checks a `txn-list-append` history. Every appended value must be unique
+/// per key, which lets the version order of each key be recovered from the
+/// longest list read of it.
+pub fn check_list_append<Val>(history: &[HistoryOp<Val, Vec<Val>>]) -> Report
+where
+    Val: Copy + Eq + Hash + Debug + Serialize,
+{
+    let writes = Writes::index(history);
+    let mut anomalies = Vec::new();
+    let mut graph = Graph::new(history.len());
+
+    let mut orders: HashMap<TxnKey, Vec<Val>> = HashMap::new();
+    for op in history.iter().filter(|op| op.t == OpType::Ok) {
+        for micro_op in &op.value {
+            let MicroOp::Read(key, Some(list)) = micro_op else {
+                continue;
+            };
+            let order = orders.entry(*key).or_default();
+            let common = order.len().min(list.len());
+            if order[..common] != list[..common] {
+                anomalies.push(Anomaly::IncompatibleOrder {
+                    key: *key,
+                    values: vec![format!("{:?}", order), format!("{:?}", list)],
+                });
+            } else if list.len() > order.len() {
+                *order = list.clone();
+            }
+        }
+    }
+
+    for (key, order) in &orders {
+        for pair in order.windows(2) {
+            let (Some(&a), Some(&b)) = (
+                writes.writer.get(&(*key, pair[0])),
+                writes.writer.get(&(*key, pair[1])),
+            ) else {
+                continue;
+            };
+            graph.link(
+                a,
+                b,
+                DepKind::WW,
+                *key,
+                format!(
+                    "T{} appended {:?} after T{} appended {:?}",
+                    b, pair[1], a, pair[0]
+                ),
+            );
+        }
+    }
+
+    for (reader, op) in history.iter().enumerate() {
+        if op.t != OpType::Ok {
+            continue;
+        }
+        for (key, list) in external_reads(op) {
+            let list = list.as_deref().unwrap_or(&[]);
+            for (i, value) in list.iter().enumerate() {
+                let observed_last = i + 1 == list.len();
+                writes.check_read(reader, key, *value, observed_last, &mut anomalies);
+            }
+
+            if let Some(&last) = list.last() {
+                if let Some(writer) = writes.writer.get(&(key, last)) {
+                    graph.link(
+                        *writer,
+                        reader,
+                        DepKind::WR,
+                        key,
+                        format!("T{} observed T{}'s append of {:?}", reader, writer, last),
+                    );
+                }
+            }
+
+            let next = orders.get(&key).and_then(|order| order.get(list.len()));
+            if let Some(&next) = next {
+                if let Some(&writer) = writes.writer.get(&(key, next)) {
+                    graph.link(
+                        reader,
+                        writer,
+                        DepKind::RW,
+                        key,
+                        format!(
+                            "T{} did not observe T{}'s append of {:?}",
+                            reader, writer, next
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    anomalies.extend(graph.cycle_anomalies(history));
+    Report { anomalies }
+}
+
+/// Checks a `txn-rw-register` history. Every written value must be unique
+/// per key. Version orders are only inferred from transactions that read a
+/// key and then wrote it, so some ww and rw dependencies may go unnoticed.
+pub fn check_rw_register<Val>(history: &[HistoryOp<Val>]) -> Report
+where
+    Val: Copy + Eq + Hash + Debug + Serialize,
+{
+    let writes = Writes::index(history);
+    let mut anomalies = Vec::new();
+    let mut graph = Graph::new(history.len());
+
+    // Successors of each version: a transaction that read `a` and then wrote
+    // `b` on the same key installed `b` right after `a`.
+    let mut next_versions: HashMap<(TxnKey, Option<Val>), Vec<Val>> = HashMap::new();
+    for op in history.iter().filter(|op| op.t == OpType::Ok) {
+        let mut read: HashMap<TxnKey, Option<Val>> = HashMap::new();
+        for micro_op in &op.value {
+            match micro_op {
+                MicroOp::Read(key, value) => {
+                    read.entry(*key).or_insert(*value);
+                }
+                MicroOp::Write(key, value) | MicroOp::Append(key, value) => {
+                    if let Some(prev) = read.insert(*key, Some(*value)) {
+                        next_versions.entry((*key, prev)).or_default().push(*value);
+                    }
+                }
+            }
+        }
+    }
+
+    for ((key, prev), nexts) in &next_versions {
+        let Some(prev) = prev else {
+            continue;
+        };
+        let Some(&a) = writes.writer.get(&(*key, *prev)) else {
+            continue;
+        };
+        for next in nexts {
+            let Some(&b) = writes.writer.get(&(*key, *next)) else {
+                continue;
+            };
+            graph.link(
+                a,
+                b,
+                DepKind::WW,
+                *key,
+                format!("T{} overwrote T{}'s {:?} with {:?}", b, a, prev, next),
+            );
+        }
+    }
+
+    for (reader, op) in history.iter().enumerate() {
+        if op.t != OpType::Ok {
+            continue;
+        }
+        for (key, value) in external_reads(op) {
+            if let Some(value) = value {
+                if let Some(writer) = writes.check_read(reader, key, *value, true, &mut anomalies) {
+                    graph.link(
+                        writer,
+                        reader,
+                        DepKind::WR,
+                        key,
+                        format!("T{} observed T{}'s write of {:?}", reader, writer, value),
+                    );
+                }
+            }
+
+            for next in next_versions.get(&(key, *value)).into_iter().flatten() {
+                let Some(&writer) = writes.writer.get(&(key, *next)) else {
+                    continue;
+                };
+                graph.link(
+                    reader,
+                    writer,
+                    DepKind::RW,
+                    key,
+                    format!(
+                        "T{} read {:?}, which T{} overwrote with {:?}",
+                        reader, value, writer, next
+                    ),
+                );
+            }
+        }
+    }
+
+    anomalies.extend(graph.cycle_anomalies(history));
+    Report { anomalies }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history<ReadVal: DeserializeOwned>(json: &str) -> Vec<HistoryOp<i64, ReadVal>> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn list_append(json: &str) -> Report {
+        check_list_append(&history::<Vec<i64>>(json))
+    }
+
+    #[test]
+    fn clean_list_append_history() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["append", 1, 1]]},
+                {"type": "ok", "process": 1, "value": [["r", 1, [1]], ["append", 1, 2]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, [1, 2]]]}
+            ]"#,
+        );
+
+        assert!(report.anomalies.is_empty(), "{:?}", report.anomalies);
+        assert!(report.is_valid(IsolationLevel::Serializable));
+    }
+
+    #[test]
+    fn g0_write_cycle() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["append", 1, 1], ["append", 2, 2]]},
+                {"type": "ok", "process": 1, "value": [["append", 1, 2], ["append", 2, 1]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, [1, 2]], ["r", 2, [1, 2]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G0"]);
+        assert!(!report.is_valid(IsolationLevel::ReadUncommitted));
+    }
+
+    #[test]
+    fn g1a_aborted_read() {
+        let report = list_append(
+            r#"[
+                {"type": "fail", "process": 0, "value": [["append", 1, 1]]},
+                {"type": "ok", "process": 1, "value": [["r", 1, [1]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G1a"]);
+        assert!(report.is_valid(IsolationLevel::ReadUncommitted));
+        assert!(!report.is_valid(IsolationLevel::ReadCommitted));
+    }
+
+    #[test]
+    fn raw_history_with_invocations() {
+        let report = list_append(
+            r#"[
+                {"type": "invoke", "process": 0, "value": [["append", 1, 1]]},
+                {"type": "invoke", "process": 1, "value": [["r", 1, null], ["append", 1, 2]]},
+                {"type": "fail", "process": 0, "value": [["append", 1, 1]]},
+                {"type": "ok", "process": 1, "value": [["r", 1, []], ["append", 1, 2]]},
+                {"type": "invoke", "process": 2, "value": [["r", 1, null]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, [1, 2]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G1a"]);
+        match &report.anomalies[0] {
+            Anomaly::G1a { reader, writer, .. } => assert_eq!((*reader, *writer), (5, 2)),
+            anomaly => panic!("unexpected {:?}", anomaly),
+        }
+    }
+
+    #[test]
+    fn g1b_intermediate_read() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["append", 1, 1], ["append", 1, 2]]},
+                {"type": "ok", "process": 1, "value": [["r", 1, [1]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G1b"]);
+        assert!(!report.is_valid(IsolationLevel::ReadCommitted));
+    }
+
+    #[test]
+    fn g1c_circular_information_flow() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["append", 1, 1], ["r", 2, [1]]]},
+                {"type": "ok", "process": 1, "value": [["append", 2, 1], ["r", 1, [1]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G1c"]);
+        assert!(!report.is_valid(IsolationLevel::ReadCommitted));
+    }
+
+    #[test]
+    fn g_single_read_skew() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["r", 1, []], ["r", 2, [1]]]},
+                {"type": "ok", "process": 1, "value": [["append", 1, 1], ["append", 2, 1]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, [1]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G-single"]);
+        assert!(report.is_valid(IsolationLevel::ReadCommitted));
+        assert!(!report.is_valid(IsolationLevel::SnapshotIsolation));
+    }
+
+    #[test]
+    fn g2_write_skew() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["r", 1, []], ["append", 2, 1]]},
+                {"type": "ok", "process": 1, "value": [["r", 2, []], ["append", 1, 1]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, [1]], ["r", 2, [1]]]}
+            ]"#,
+        );
+
+        assert_eq!(report.anomaly_types(), vec!["G2"]);
+        assert!(report.is_valid(IsolationLevel::SnapshotIsolation));
+        assert!(!report.is_valid(IsolationLevel::Serializable));
+    }
+
+    #[test]
+    fn incompatible_orders() {
+        let report = list_append(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["append", 1, 1]]},
+                {"type": "ok", "process": 1, "value": [["append", 1, 2]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, [1, 2]]]},
+                {"type": "ok", "process": 3, "value": [["r", 1, [2, 1]]]}
+            ]"#,
+        );
+
+        assert!(report.anomaly_types().contains(&"incompatible-order"));
+        assert!(!report.is_valid(IsolationLevel::ReadCommitted));
+    }
+
+    #[test]
+    fn clean_rw_register_history() {
+        let report = check_rw_register(&history::<i64>(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["w", 1, 1]]},
+                {"type": "ok", "process": 1, "value": [["r", 1, 1], ["w", 1, 2]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, 2]]}
+            ]"#,
+        ));
+
+        assert!(report.anomalies.is_empty(), "{:?}", report.anomalies);
+    }
+
+    #[test]
+    fn g_single_rw_register() {
+        let report = check_rw_register(&history::<i64>(
+            r#"[
+                {"type": "ok", "process": 0, "value": [["w", 1, 1]]},
+                {"type": "ok", "process": 1, "value": [["r", 1, 1], ["w", 1, 2], ["w", 2, 5]]},
+                {"type": "ok", "process": 2, "value": [["r", 1, 1], ["r", 2, 5]]}
+            ]"#,
+        ));
+
+        assert_eq!(report.anomaly_types(), vec!["G-single"]);
+    }
+}
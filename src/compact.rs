@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::node::NodeId;
+
+/// A set of integers on the wire as a flat JSON array of runs. Each run of
+/// consecutive integers `start..=end` is written as `start - prev, end - start`,
+/// where `prev` is the end of the run before (0 for the first), so dense sets
+/// of values that arrive in order shrink to a few small numbers. Differences
+/// wrap around, so values across the whole `i64` range still round-trip.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Runs(Vec<i64>);
+
+impl Runs {
+    pub fn encode<I: IntoIterator<Item = i64>>(values: I) -> Self {
+        let mut values: Vec<i64> = values.into_iter().collect();
+        values.sort_unstable();
+        values.dedup();
+
+        let mut encoded = Vec::new();
+        let mut prev = 0;
+        let mut values = values.into_iter().peekable();
+        while let Some(start) = values.next() {
+            let mut end = start;
+            while end
+                .checked_add(1)
+                .is_some_and(|next| values.peek() == Some(&next))
+            {
+                end = values.next().unwrap();
+            }
+            encoded.push(start.wrapping_sub(prev));
+            encoded.push(end.wrapping_sub(start));
+            prev = end;
+        }
+
+        Runs(encoded)
+    }
+
+    pub fn decode(self: &Self) -> impl Iterator<Item = i64> + '_ {
+        let mut prev: i64 = 0;
+        self.0.chunks_exact(2).flat_map(move |run| {
+            let start = prev.wrapping_add(run[0]);
+            prev = start.wrapping_add(run[1]);
+            start..=prev
+        })
+    }
+}
+
+/// A set of values of any type on the wire: `Runs` when every value is an
+/// integer, so integers keep the compact form, or else the plain values.
+/// A flat array of integers is always `Runs`, so the two never mix up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ValueSet {
+    Runs(Runs),
+    Plain(Vec<Value>),
+}
+
+impl ValueSet {
+    pub fn encode<'a, T, I>(values: I) -> Self
+    where
+        T: Serialize + 'a,
+        I: IntoIterator<Item = &'a T>,
+    {
+        let values: Vec<Value> = values
+            .into_iter()
+            .map(|value| serde_json::to_value(value).unwrap())
+            .collect();
+
+        match values
+            .iter()
+            .map(Value::as_i64)
+            .collect::<Option<Vec<i64>>>()
+        {
+            Some(ints) => ValueSet::Runs(Runs::encode(ints)),
+            None => ValueSet::Plain(values),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self: &Self) -> serde_json::Result<Vec<T>> {
+        match self {
+            ValueSet::Runs(runs) => runs
+                .decode()
+                .map(|int| serde_json::from_value(int.into()))
+                .collect(),
+            ValueSet::Plain(values) => values.iter().map(|value| T::deserialize(value)).collect(),
+        }
+    }
+}
+
+/// A set of nodes on the wire as a hex bitmap over their position in the
+/// `node_ids` every node got at init, so 25 nodes take 7 characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NodeBitmap(String);
+
+impl NodeBitmap {
+    /// Nodes that are not in `node_ids` are left out.
+    pub fn encode<'a, I: IntoIterator<Item = &'a NodeId>>(nodes: I, node_ids: &[NodeId]) -> Self {
+        let mut nibbles = vec![0u8; node_ids.len().div_ceil(4)];
+        for node in nodes {
+            if let Some(i) = node_ids.iter().position(|id| id == node) {
+                nibbles[i / 4] |= 1 << (i % 4);
+            }
+        }
+
+        NodeBitmap(
+            nibbles
+                .into_iter()
+                .map(|nibble| char::from_digit(nibble as u32, 16).unwrap())
+                .collect(),
+        )
+    }
+
+    pub fn decode(self: &Self, node_ids: &[NodeId]) -> HashSet<NodeId> {
+        let nibbles = self.0.chars().map(|c| c.to_digit(16).unwrap_or(0));
+        nibbles
+            .enumerate()
+            .flat_map(|(i, nibble)| {
+                (0..4)
+                    .filter(move |bit| nibble & (1 << bit) != 0)
+                    .map(move |bit| i * 4 + bit)
+            })
+            .filter_map(|i| node_ids.get(i).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(values: &[i64]) -> Vec<i64> {
+        let runs = Runs::encode(values.iter().copied());
+        let json = serde_json::to_string(&runs).unwrap();
+        let runs: Runs = serde_json::from_str(&json).unwrap();
+        runs.decode().collect()
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        assert_eq!(round_trip(&[]), Vec::<i64>::new());
+        assert_eq!(round_trip(&[3, 1, 2, 2, 7, 8, 10]), vec![1, 2, 3, 7, 8, 10]);
+        assert_eq!(round_trip(&[-5, -4, 0, 4]), vec![-5, -4, 0, 4]);
+        assert_eq!(Runs::encode(1..=1000), Runs(vec![1, 999]));
+    }
+
+    #[test]
+    fn runs_round_trip_at_the_ends_of_the_range() {
+        let values = [i64::MIN, i64::MIN + 1, -1, 0, i64::MAX - 1, i64::MAX];
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(round_trip(&[i64::MIN, i64::MAX]), vec![i64::MIN, i64::MAX]);
+        assert_eq!(round_trip(&[i64::MAX]), vec![i64::MAX]);
+    }
+
+    #[test]
+    fn value_sets_keep_their_values() {
+        let ints = ValueSet::encode(&[1, 2, 3, 5]);
+        assert!(matches!(ints, ValueSet::Runs(_)));
+        assert_eq!(ints.decode::<i64>().unwrap(), vec![1, 2, 3, 5]);
+
+        let strings = ValueSet::encode(&["a", "b"]);
+        assert_eq!(strings.decode::<String>().unwrap(), vec!["a", "b"]);
+        assert!(strings.decode::<i64>().is_err());
+    }
+
+    #[test]
+    fn node_bitmaps_round_trip() {
+        let node_ids: Vec<NodeId> = (0..25).map(|i| format!("n{}", i)).collect();
+        let nodes: HashSet<NodeId> = ["n0", "n5", "n24"].map(String::from).into();
+
+        let bitmap = NodeBitmap::encode(&nodes, &node_ids);
+        assert_eq!(bitmap.0.len(), 7);
+        assert_eq!(bitmap.decode(&node_ids), nodes);
+    }
+}
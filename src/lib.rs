@@ -1,2 +1,12 @@
+pub mod causal;
+pub mod checker;
+pub mod compact;
+pub mod log_store;
 pub mod messages;
 pub mod node;
+pub mod propagate;
+pub mod raft;
+#[cfg(test)]
+mod sim;
+pub mod topology;
+pub mod total_order;
@@ -0,0 +1,854 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    hash::Hash,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub type Offset = usize;
+
+const HEADER_LEN: u64 = 8 + 4;
+const SEGMENT_EXT: &str = "log";
+const COMPACTING_EXT: &str = "compacting";
+
+#[derive(Debug, Clone)]
+pub struct LogStoreConfig {
+    /// A segment is rolled once it grows past this many bytes.
+    pub segment_bytes: u64,
+    /// Bytes of records between two entries of the sparse offset index.
+    pub index_interval_bytes: u64,
+    /// Closed segments last written longer ago than this are deleted.
+    pub retention: Option<Duration>,
+    /// Oldest closed segments are deleted while a key's log is larger.
+    pub retention_bytes: Option<u64>,
+}
+
+impl Default for LogStoreConfig {
+    fn default() -> Self {
+        LogStoreConfig {
+            segment_bytes: 1 << 20,
+            index_interval_bytes: 4 << 10,
+            retention: None,
+            retention_bytes: None,
+        }
+    }
+}
+
+/// One append-only segment file, named after the first offset it may hold.
+/// Records are `[offset: u64][len: u32][json value]`, little endian.
+#[derive(Debug)]
+struct Segment {
+    base_offset: Offset,
+    path: PathBuf,
+    size: u64,
+    last_offset: Option<Offset>,
+    /// Sparse `(offset, byte position)` pairs, in offset order.
+    index: Vec<(Offset, u64)>,
+    indexed_at: u64,
+}
+
+impl Segment {
+    fn path_for(dir: &Path, base_offset: Offset) -> PathBuf {
+        dir.join(format!("{:020}.{}", base_offset, SEGMENT_EXT))
+    }
+
+    fn create(dir: &Path, base_offset: Offset) -> io::Result<Self> {
+        let path = Segment::path_for(dir, base_offset);
+        File::create(&path)?;
+
+        Ok(Segment {
+            base_offset,
+            path,
+            size: 0,
+            last_offset: None,
+            index: Vec::new(),
+            indexed_at: 0,
+        })
+    }
+
+    /// Rebuilds the index of an existing segment, cutting off a record that
+    /// was only partially written before a crash.
+    fn recover(path: PathBuf, base_offset: Offset, config: &LogStoreConfig) -> io::Result<Self> {
+        let mut segment = Segment {
+            base_offset,
+            path,
+            size: 0,
+            last_offset: None,
+            index: Vec::new(),
+            indexed_at: 0,
+        };
+
+        let file = File::open(&segment.path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut pos = 0;
+        while let Some((offset, payload_len)) = read_header(&mut reader)? {
+            let end = pos + HEADER_LEN + payload_len as u64;
+            if end > len {
+                break;
+            }
+            reader.seek_relative(payload_len as i64)?;
+            segment.track(offset, pos, end, config);
+            pos = end;
+        }
+
+        if pos < len {
+            eprintln!(
+                "truncating torn record in {:?} at byte {}",
+                segment.path, pos
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&segment.path)?
+                .set_len(pos)?;
+        }
+
+        Ok(segment)
+    }
+
+    fn track(self: &mut Self, offset: Offset, pos: u64, end: u64, config: &LogStoreConfig) -> () {
+        if self.index.is_empty() || pos - self.indexed_at >= config.index_interval_bytes {
+            self.index.push((offset, pos));
+            self.indexed_at = pos;
+        }
+        self.last_offset = Some(offset);
+        self.size = end;
+    }
+
+    fn append(
+        self: &mut Self,
+        offset: Offset,
+        payload: &[u8],
+        config: &LogStoreConfig,
+    ) -> io::Result<()> {
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        write_record(&mut record, offset, payload)?;
+
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&record)?;
+        // Appends are acknowledged to clients, so they must survive a crash.
+        file.sync_data()?;
+
+        let pos = self.size;
+        self.track(offset, pos, pos + record.len() as u64, config);
+        Ok(())
+    }
+
+    /// Byte position to start scanning from to find `offset`.
+    fn seek_position(self: &Self, offset: Offset) -> u64 {
+        match self.index.partition_point(|(o, _)| *o <= offset) {
+            0 => 0,
+            i => self.index[i - 1].1,
+        }
+    }
+
+    /// Calls `f` with each raw record at or after `offset` until it returns
+    /// false.
+    fn scan<F>(self: &Self, offset: Offset, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Offset, Vec<u8>) -> io::Result<bool>,
+    {
+        let mut file = File::open(&self.path)?;
+        let pos = self.seek_position(offset);
+        file.seek(SeekFrom::Start(pos))?;
+        let mut reader = BufReader::new(file).take(self.size - pos);
+
+        while let Some((record_offset, payload_len)) = read_header(&mut reader)? {
+            let mut payload = vec![0; payload_len as usize];
+            reader.read_exact(&mut payload)?;
+            if record_offset < offset {
+                continue;
+            }
+            if !f(record_offset, payload)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_from<Val>(
+        self: &Self,
+        offset: Offset,
+        limit: &mut ReadLimit,
+        out: &mut Vec<(Offset, Val)>,
+    ) -> io::Result<()>
+    where
+        Val: DeserializeOwned,
+    {
+        self.scan(offset, |record_offset, payload| {
+            if !limit.take(payload.len()) {
+                return Ok(false);
+            }
+            out.push((record_offset, decode(&payload)?));
+            Ok(true)
+        })
+    }
+
+    fn modified(self: &Self) -> io::Result<SystemTime> {
+        fs::metadata(&self.path)?.modified()
+    }
+}
+
+/// What a read may still return. The first record is always returned, even
+/// when it alone is over `bytes`, so readers keep making progress.
+struct ReadLimit {
+    records: usize,
+    bytes: usize,
+    first: bool,
+}
+
+impl ReadLimit {
+    fn take(self: &mut Self, len: usize) -> bool {
+        if self.records == 0 || (len > self.bytes && !self.first) {
+            return false;
+        }
+        self.records -= 1;
+        self.bytes = self.bytes.saturating_sub(len);
+        self.first = false;
+        true
+    }
+
+    fn exhausted(self: &Self) -> bool {
+        self.records == 0 || (self.bytes == 0 && !self.first)
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, offset: Offset, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(offset as u64).to_le_bytes())?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+fn decode<Val: DeserializeOwned>(payload: &[u8]) -> io::Result<Val> {
+    serde_json::from_slice(payload).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<(Offset, u32)>> {
+    let mut header = [0; HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let offset = u64::from_le_bytes(header[..8].try_into().unwrap()) as Offset;
+    let len = u32::from_le_bytes(header[8..].try_into().unwrap());
+    Ok(Some((offset, len)))
+}
+
+/// The segments of one key, oldest first. Offsets only grow, but may have
+/// gaps.
+#[derive(Debug)]
+struct KeyLog {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+}
+
+impl KeyLog {
+    fn open(dir: PathBuf, config: &LogStoreConfig) -> io::Result<Self> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(SEGMENT_EXT) => (),
+                // Left behind by a compaction that never finished.
+                Some(COMPACTING_EXT) => {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                _ => continue,
+            }
+            let Some(base_offset) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            else {
+                continue;
+            };
+            segments.push(Segment::recover(path, base_offset, config)?);
+        }
+        segments.sort_by_key(|s| s.base_offset);
+
+        Ok(KeyLog { dir, segments })
+    }
+
+    fn next_offset(self: &Self) -> Option<Offset> {
+        self.segments
+            .iter()
+            .rev()
+            .find_map(|s| s.last_offset)
+            .map(|o| o + 1)
+    }
+
+    /// Deletes the oldest segments while `expired` says so. The active
+    /// segment is always kept, so the log end survives. Returns the first
+    /// offset left if any segment was deleted.
+    fn delete_prefix<F>(self: &mut Self, mut expired: F) -> io::Result<Option<Offset>>
+    where
+        F: FnMut(&Segment, u64) -> io::Result<bool>,
+    {
+        let mut size: u64 = self.segments.iter().map(|s| s.size).sum();
+        let mut deleted = false;
+        while self.segments.len() > 1 && expired(&self.segments[0], size)? {
+            let segment = self.segments.remove(0);
+            fs::remove_file(&segment.path)?;
+            size -= segment.size;
+            deleted = true;
+        }
+
+        Ok(deleted.then(|| self.segments[0].base_offset))
+    }
+}
+
+/// Append-only, segmented storage of per-key logs on disk. Only a sparse
+/// index stays in memory; records are read back from the segment files.
+pub struct LogStore<Val> {
+    dir: PathBuf,
+    config: LogStoreConfig,
+    logs: HashMap<String, KeyLog>,
+    _val: PhantomData<Val>,
+}
+
+fn key_dir_name(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key_from_dir_name(name: &str) -> Option<String> {
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+impl<Val> LogStore<Val>
+where
+    Val: Serialize + DeserializeOwned,
+{
+    /// Opens the store in `dir`, recovering every key log already there.
+    pub fn open(dir: impl AsRef<Path>, config: LogStoreConfig) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut logs = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(key) = entry.file_name().to_str().and_then(key_from_dir_name) else {
+                continue;
+            };
+            logs.insert(key, KeyLog::open(entry.path(), &config)?);
+        }
+
+        Ok(LogStore {
+            dir,
+            config,
+            logs,
+            _val: PhantomData,
+        })
+    }
+
+    pub fn keys(self: &Self) -> impl Iterator<Item = &String> {
+        self.logs.keys()
+    }
+
+    pub fn contains_key(self: &Self, key: &str) -> bool {
+        self.logs.contains_key(key)
+    }
+
+    /// One past the last offset stored for `key`.
+    pub fn next_offset(self: &Self, key: &str) -> Option<Offset> {
+        self.logs.get(key)?.next_offset()
+    }
+
+    /// Appends a record; offsets of a key must be strictly increasing.
+    pub fn append(self: &mut Self, key: &str, offset: Offset, value: &Val) -> io::Result<()> {
+        let payload = serde_json::to_vec(value)?;
+
+        if !self.logs.contains_key(key) {
+            let dir = self.dir.join(key_dir_name(key));
+            fs::create_dir_all(&dir)?;
+            self.logs.insert(
+                key.to_string(),
+                KeyLog {
+                    dir,
+                    segments: Vec::new(),
+                },
+            );
+        }
+        let log = self.logs.get_mut(key).unwrap();
+
+        if let Some(next) = log.next_offset() {
+            if offset < next {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "offset {} of {} is behind the log end {}",
+                        offset, key, next
+                    ),
+                ));
+            }
+        }
+
+        let roll = log
+            .segments
+            .last()
+            .is_none_or(|s| s.size >= self.config.segment_bytes);
+        if roll {
+            log.segments.push(Segment::create(&log.dir, offset)?);
+        }
+
+        log.segments
+            .last_mut()
+            .unwrap()
+            .append(offset, &payload, &self.config)
+    }
+
+    /// Adds the records at offsets `key` does not have yet, including ones
+    /// below its end, e.g. gaps that another copy of the log has filled.
+    /// Segments that get records are rewritten aside and swapped in one by
+    /// one, so each is either old or new after a crash. Returns how many
+    /// records were added.
+    pub fn insert(self: &mut Self, key: &str, records: Vec<(Offset, Val)>) -> io::Result<usize> {
+        let end = self.next_offset(key);
+        let (mut inner, past): (Vec<_>, Vec<_>) = records
+            .into_iter()
+            .partition(|(offset, _)| end.is_some_and(|end| *offset < end));
+        let mut added = 0;
+
+        if !inner.is_empty() {
+            inner.sort_by_key(|(offset, _)| *offset);
+            let first = inner[0].0;
+            let log = self.logs.get_mut(key).unwrap();
+            // Each record goes to the segment whose offsets it falls in, or
+            // to a new first one.
+            let mut targets: Vec<(PathBuf, _, Vec<_>)> = Vec::new();
+            for (offset, value) in inner {
+                let payload = serde_json::to_vec(&value)?;
+                let segment = log
+                    .segments
+                    .partition_point(|s| s.base_offset <= offset)
+                    .checked_sub(1)
+                    .map(|i| &log.segments[i]);
+                let path = match segment {
+                    Some(segment) => segment.path.clone(),
+                    None => Segment::path_for(&log.dir, first),
+                };
+                if targets.last().is_none_or(|(last, ..)| *last != path) {
+                    targets.push((path, segment, Vec::new()));
+                }
+                targets.last_mut().unwrap().2.push((offset, payload));
+            }
+
+            let mut swapped = Ok(());
+            for (path, segment, records) in targets {
+                let tmp = path.with_extension(COMPACTING_EXT);
+                let written = write_merged(segment, &tmp, records);
+                swapped = written.and_then(|n| {
+                    added += n;
+                    fs::rename(&tmp, &path)
+                });
+                if swapped.is_err() {
+                    let _ = fs::remove_file(&tmp);
+                    break;
+                }
+            }
+            *log = KeyLog::open(log.dir.clone(), &self.config)?;
+            swapped?;
+        }
+
+        for (offset, value) in past {
+            if self.next_offset(key).is_some_and(|end| offset < end) {
+                continue;
+            }
+            self.append(key, offset, &value)?;
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    /// Reads the records of `key` at or after `offset`, up to `max_records`
+    /// of them and `max_bytes` of encoded values. An offset past the log end
+    /// reads nothing.
+    pub fn read_from(
+        self: &Self,
+        key: &str,
+        offset: Offset,
+        max_records: usize,
+        max_bytes: usize,
+    ) -> io::Result<Vec<(Offset, Val)>> {
+        let mut limit = ReadLimit {
+            records: max_records,
+            bytes: max_bytes,
+            first: true,
+        };
+        let mut out = Vec::new();
+        let Some(log) = self.logs.get(key) else {
+            return Ok(out);
+        };
+
+        let first = log
+            .segments
+            .partition_point(|s| s.base_offset <= offset)
+            .saturating_sub(1);
+        for segment in &log.segments[first..] {
+            if limit.exhausted() {
+                break;
+            }
+            if segment.last_offset.is_none_or(|last| last < offset) {
+                continue;
+            }
+            segment.read_from(offset, &mut limit, &mut out)?;
+        }
+
+        Ok(out)
+    }
+
+    /// First offset still stored for `key`; earlier ones were truncated.
+    pub fn start_offset(self: &Self, key: &str) -> Option<Offset> {
+        self.logs.get(key)?.segments.first().map(|s| s.base_offset)
+    }
+
+    /// Drops the closed segments of `key` that are past the time or size
+    /// retention, or that only hold offsets below `committed`. Returns the
+    /// first offset left if any segment was dropped.
+    pub fn retain(
+        self: &mut Self,
+        key: &str,
+        committed: Option<Offset>,
+    ) -> io::Result<Option<Offset>> {
+        let Some(log) = self.logs.get_mut(key) else {
+            return Ok(None);
+        };
+        let now = SystemTime::now();
+        let config = &self.config;
+
+        log.delete_prefix(|segment, size| {
+            if committed.is_some_and(|c| segment.last_offset.is_none_or(|last| last < c)) {
+                return Ok(true);
+            }
+            if config.retention_bytes.is_some_and(|max| size > max) {
+                return Ok(true);
+            }
+            let Some(retention) = config.retention else {
+                return Ok(false);
+            };
+            let age = now.duration_since(segment.modified()?).unwrap_or_default();
+            Ok(age > retention)
+        })
+    }
+
+    /// Drops the closed segments of `key` that only hold offsets below
+    /// `offset`, so another copy of the log can be cut where this one was.
+    pub fn delete_before(self: &mut Self, key: &str, offset: Offset) -> io::Result<()> {
+        let Some(log) = self.logs.get_mut(key) else {
+            return Ok(());
+        };
+
+        log.delete_prefix(|segment, _| Ok(segment.last_offset.is_none_or(|last| last < offset)))?;
+        Ok(())
+    }
+
+    /// Rewrites the closed segments of `key` to keep only the last record of
+    /// every record key, as given by `record_key`, counting the records of
+    /// the active segment too. Compacted segments are all written aside
+    /// before any replaces the original, so a failure leaves the log intact.
+    pub fn compact<K, F>(self: &mut Self, key: &str, record_key: F) -> io::Result<()>
+    where
+        K: Eq + Hash,
+        F: Fn(&Val) -> K,
+    {
+        let Some(log) = self.logs.get_mut(key) else {
+            return Ok(());
+        };
+        if log.segments.len() < 2 {
+            return Ok(());
+        }
+
+        let mut last = HashMap::new();
+        for segment in &log.segments {
+            segment.scan(0, |offset, payload| {
+                last.insert(record_key(&decode::<Val>(&payload)?), offset);
+                Ok(true)
+            })?;
+        }
+
+        let closed = &log.segments[..log.segments.len() - 1];
+        let mut rewritten: Vec<(PathBuf, usize)> = Vec::with_capacity(closed.len());
+        for segment in closed {
+            let tmp = segment.path.with_extension(COMPACTING_EXT);
+            let written = write_compacted(segment, &tmp, |offset, payload| {
+                Ok(last.get(&record_key(&decode::<Val>(payload)?)) == Some(&offset))
+            });
+            match written {
+                Ok(kept) => rewritten.push((tmp, kept)),
+                Err(e) => {
+                    let _ = fs::remove_file(&tmp);
+                    for (tmp, _) in &rewritten {
+                        let _ = fs::remove_file(tmp);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let swapped =
+            closed
+                .iter()
+                .zip(&rewritten)
+                .try_for_each(|(segment, (tmp, kept))| match kept {
+                    0 => fs::remove_file(tmp).and_then(|()| fs::remove_file(&segment.path)),
+                    _ => fs::rename(tmp, &segment.path),
+                });
+        // Some segments may be swapped even on failure, so the files are
+        // read back either way.
+        *log = KeyLog::open(log.dir.clone(), &self.config)?;
+
+        swapped
+    }
+}
+
+/// Copies the records of `segment` that `keep` accepts to `tmp`, synced to
+/// disk. Returns how many were kept.
+fn write_compacted<F>(segment: &Segment, tmp: &Path, mut keep: F) -> io::Result<usize>
+where
+    F: FnMut(Offset, &[u8]) -> io::Result<bool>,
+{
+    let mut writer = BufWriter::new(File::create(tmp)?);
+    let mut kept = 0;
+    segment.scan(0, |offset, payload| {
+        if keep(offset, &payload)? {
+            write_record(&mut writer, offset, &payload)?;
+            kept += 1;
+        }
+        Ok(true)
+    })?;
+    writer.into_inner()?.sync_all()?;
+
+    Ok(kept)
+}
+
+/// Writes the records of `segment`, if any, together with `records` to
+/// `tmp` in offset order, synced to disk. Records at offsets `segment`
+/// already has are left out. Returns how many of `records` were written.
+fn write_merged(
+    segment: Option<&Segment>,
+    tmp: &Path,
+    records: Vec<(Offset, Vec<u8>)>,
+) -> io::Result<usize> {
+    let mut writer = BufWriter::new(File::create(tmp)?);
+    let mut records = records.into_iter().peekable();
+    let mut added = 0;
+    let mut write_before = |writer: &mut BufWriter<File>, offset: Option<Offset>| {
+        while let Some((next, payload)) = records.next_if(|(o, _)| offset.is_none_or(|x| *o <= x)) {
+            if Some(next) != offset {
+                write_record(writer, next, &payload)?;
+                added += 1;
+            }
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    if let Some(segment) = segment {
+        segment.scan(0, |offset, payload| {
+            write_before(&mut writer, Some(offset))?;
+            write_record(&mut writer, offset, &payload)?;
+            Ok(true)
+        })?;
+    }
+    write_before(&mut writer, None)?;
+    writer.into_inner()?.sync_all()?;
+
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Rolls a segment after every record.
+    fn tiny_segments() -> LogStoreConfig {
+        LogStoreConfig {
+            segment_bytes: 1,
+            ..LogStoreConfig::default()
+        }
+    }
+
+    type Record = (String, u32);
+
+    fn record(key: &str, value: u32) -> Record {
+        (key.to_string(), value)
+    }
+
+    fn fill(store: &mut LogStore<Record>) -> () {
+        let records = [
+            record("a", 1),
+            record("b", 2),
+            record("a", 3),
+            record("c", 4),
+        ];
+        for (offset, record) in records.iter().enumerate() {
+            store.append("k", offset, record).unwrap();
+        }
+    }
+
+    #[test]
+    fn recovers_records_on_open() {
+        let dir = temp_dir("recover");
+        let mut store = LogStore::open(&dir, tiny_segments()).unwrap();
+        fill(&mut store);
+        let before = store.read_from("k", 0, usize::MAX, usize::MAX).unwrap();
+
+        let store: LogStore<Record> = LogStore::open(&dir, tiny_segments()).unwrap();
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            before
+        );
+        assert_eq!(store.next_offset("k"), Some(4));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_the_last_record_of_each_key() {
+        let dir = temp_dir("compact");
+        let mut store = LogStore::open(&dir, tiny_segments()).unwrap();
+        fill(&mut store);
+
+        store.compact("k", |(key, _)| key.clone()).unwrap();
+        let expected = vec![
+            (1, record("b", 2)),
+            (2, record("a", 3)),
+            (3, record("c", 4)),
+        ];
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            expected
+        );
+        assert_eq!(store.next_offset("k"), Some(4));
+
+        let store: LogStore<Record> = LogStore::open(&dir, tiny_segments()).unwrap();
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            expected
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_by_value_keeps_unique_values() {
+        let dir = temp_dir("compact-unique");
+        let mut store = LogStore::open(&dir, tiny_segments()).unwrap();
+        fill(&mut store);
+        let before = store.read_from("k", 0, usize::MAX, usize::MAX).unwrap();
+
+        store.compact("k", |record| record.clone()).unwrap();
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            before
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_compaction_leaves_the_log_intact() {
+        let dir = temp_dir("compact-fail");
+        let mut store = LogStore::open(&dir, tiny_segments()).unwrap();
+        fill(&mut store);
+        let before = store.read_from("k", 0, usize::MAX, usize::MAX).unwrap();
+
+        // A directory in the way of the second segment's rewrite.
+        let key_dir = dir.join(key_dir_name("k"));
+        fs::create_dir(Segment::path_for(&key_dir, 1).with_extension(COMPACTING_EXT)).unwrap();
+
+        assert!(store.compact("k", |(key, _)| key.clone()).is_err());
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            before
+        );
+        assert_eq!(store.next_offset("k"), Some(4));
+        assert!(!Segment::path_for(&key_dir, 0)
+            .with_extension(COMPACTING_EXT)
+            .exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn insert_fills_the_offsets_a_log_lacks() {
+        let dir = temp_dir("insert");
+        let mut store = LogStore::open(&dir, tiny_segments()).unwrap();
+        for offset in [2, 5, 10] {
+            store
+                .append("k", offset, &record("old", offset as u32))
+                .unwrap();
+        }
+
+        let records = [0, 1, 3, 5, 7, 12]
+            .into_iter()
+            .map(|offset| (offset, record("new", offset as u32)))
+            .collect();
+        assert_eq!(store.insert("k", records).unwrap(), 5);
+        let expected = vec![
+            (0, record("new", 0)),
+            (1, record("new", 1)),
+            (2, record("old", 2)),
+            (3, record("new", 3)),
+            (5, record("old", 5)),
+            (7, record("new", 7)),
+            (10, record("old", 10)),
+            (12, record("new", 12)),
+        ];
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            expected
+        );
+        assert_eq!(store.next_offset("k"), Some(13));
+
+        let store: LogStore<Record> = LogStore::open(&dir, tiny_segments()).unwrap();
+        assert_eq!(
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            expected
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retention_reports_where_it_cut_and_copies_cut_there() {
+        let dir = temp_dir("retain");
+        let mut store = LogStore::open(dir.join("owner"), tiny_segments()).unwrap();
+        let mut copy = LogStore::open(dir.join("copy"), tiny_segments()).unwrap();
+        fill(&mut store);
+        fill(&mut copy);
+
+        assert_eq!(store.retain("k", Some(0)).unwrap(), None);
+        assert_eq!(store.retain("k", Some(2)).unwrap(), Some(2));
+        assert_eq!(store.start_offset("k"), Some(2));
+
+        copy.delete_before("k", 2).unwrap();
+        assert_eq!(
+            copy.read_from("k", 0, usize::MAX, usize::MAX).unwrap(),
+            store.read_from("k", 0, usize::MAX, usize::MAX).unwrap()
+        );
+
+        // The active segment stays, whatever the cut.
+        copy.delete_before("k", 10).unwrap();
+        assert_eq!(copy.start_offset("k"), Some(3));
+        assert_eq!(copy.next_offset("k"), Some(4));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
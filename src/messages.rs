@@ -1,10 +1,16 @@
 pub mod broadcast;
+pub mod causal;
 pub mod echo;
 pub mod error;
 pub mod generate;
 pub mod init;
+pub mod kv;
+pub mod propagate;
+pub mod raft;
 pub mod read;
 pub mod topology;
+pub mod total_order;
+pub mod txn;
 
 use std::fmt::Debug;
 
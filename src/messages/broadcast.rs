@@ -1,3 +1,5 @@
+use std::hash::{Hash, Hasher};
+
 use serde::{Deserialize, Serialize};
 
 use super::MsgId;
@@ -12,3 +14,15 @@ pub struct BroadcastBody<Val = i32> {
 pub struct BroadcastOkBody {
     pub in_reply_to: MsgId,
 }
+
+/// Any JSON value as a broadcast message. `serde_json::Value` is not `Hash`,
+/// so this hashes the JSON text, whose object keys are always sorted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct JsonVal(pub serde_json::Value);
+
+impl Hash for JsonVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state);
+    }
+}
@@ -0,0 +1,19 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::node::NodeId;
+
+/// How many messages from each origin a node had delivered. Origins it has
+/// delivered nothing from are left out.
+pub type VectorClock = BTreeMap<NodeId, u64>;
+
+/// A causally broadcast value, disseminated as a `Propagator` value. The
+/// clock counts this message itself for `origin`, so `(origin, clock[origin])`
+/// identifies it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct CausalMsg<Val> {
+    pub origin: NodeId,
+    pub clock: VectorClock,
+    pub value: Val,
+}
@@ -2,6 +2,17 @@ use serde::{Deserialize, Serialize};
 
 use super::MsgId;
 
+pub const TIMEOUT: u32 = 0;
+pub const NOT_SUPPORTED: u32 = 10;
+pub const TEMPORARILY_UNAVAILABLE: u32 = 11;
+pub const MALFORMED_REQUEST: u32 = 12;
+pub const CRASH: u32 = 13;
+pub const ABORT: u32 = 14;
+pub const KEY_DOES_NOT_EXIST: u32 = 20;
+pub const KEY_ALREADY_EXISTS: u32 = 21;
+pub const PRECONDITION_FAILED: u32 = 22;
+pub const TXN_CONFLICT: u32 = 30;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ErrorBody {
     pub in_reply_to: MsgId,
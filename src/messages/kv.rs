@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use super::MsgId;
+
+pub const LIN_KV: &str = "lin-kv";
+pub const SEQ_KV: &str = "seq-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvReadBody<Key = String> {
+    pub msg_id: MsgId,
+    pub key: Key,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvReadOkBody<Val> {
+    pub in_reply_to: MsgId,
+    pub value: Val,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvWriteBody<Key, Val> {
+    pub msg_id: MsgId,
+    pub key: Key,
+    pub value: Val,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct KvWriteOkBody {
+    pub in_reply_to: MsgId,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvCasBody<Key, Val> {
+    pub msg_id: MsgId,
+    pub key: Key,
+    pub from: Val,
+    pub to: Val,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_if_not_exists: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct KvCasOkBody {
+    pub in_reply_to: MsgId,
+}
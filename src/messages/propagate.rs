@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+use crate::compact::{NodeBitmap, ValueSet};
+
+pub type PushId = u64;
+
+/// Messages between `Propagator`s, carried by a single `propagate` message
+/// type like `RaftMsg`. Only pushes are acknowledged; everything else is
+/// repaired by later rounds if it gets lost.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "rpc", rename_all = "snake_case")]
+pub enum PropagateMsg {
+    /// Values for a neighbour, and the nodes it need not forward them to.
+    Push {
+        id: PushId,
+        values: ValueSet,
+        known_nodes: NodeBitmap,
+    },
+    PushOk {
+        id: PushId,
+    },
+    /// Pushes fresh values, with the digest of every bucket of the values
+    /// the sender has.
+    Gossip {
+        values: ValueSet,
+        digests: Vec<u64>,
+    },
+    /// Pulls: the receiver's values in the buckets whose digests differed,
+    /// less those the sender is known to have.
+    GossipRes {
+        values: ValueSet,
+    },
+    /// Announces values to a lazy peer without sending them.
+    #[serde(rename = "ihave")]
+    IHave {
+        values: ValueSet,
+    },
+    /// Asks a lazy peer for values and makes it eager again.
+    Graft {
+        values: ValueSet,
+    },
+    /// Tells an eager peer that its pushes are redundant.
+    Prune,
+    /// Starts an anti-entropy round with the digest of every bucket.
+    SyncDigest {
+        digests: Vec<u64>,
+    },
+    /// The receiver's values in the buckets whose digests differed.
+    SyncDigestRes {
+        buckets: Vec<usize>,
+        values: ValueSet,
+    },
+    /// Values in those buckets that the receiver turned out to miss.
+    SyncValues {
+        values: ValueSet,
+    },
+}
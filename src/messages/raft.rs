@@ -0,0 +1,69 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::node::NodeId;
+
+pub type Term = u64;
+pub type LogIndex = u64;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum EntryKind<Command> {
+    Noop,
+    Command(Command),
+    Config(Vec<NodeId>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound(
+    serialize = "Command: Serialize",
+    deserialize = "Command: DeserializeOwned"
+))]
+pub struct Entry<Command> {
+    pub term: Term,
+    pub kind: EntryKind<Command>,
+}
+
+/// Raft RPCs, carried by a single `raft` message type so a node only has to
+/// register one handler for all of them. Responses are plain one-way
+/// messages rather than replies, since Raft already tolerates lost and
+/// duplicated messages.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "rpc", rename_all = "snake_case")]
+#[serde(bound(
+    serialize = "Command: Serialize, Snapshot: Serialize",
+    deserialize = "Command: DeserializeOwned, Snapshot: DeserializeOwned"
+))]
+pub enum RaftMsg<Command, Snapshot> {
+    RequestVote {
+        term: Term,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    RequestVoteRes {
+        term: Term,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: Term,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<Entry<Command>>,
+        leader_commit: LogIndex,
+    },
+    AppendEntriesRes {
+        term: Term,
+        success: bool,
+        last_index: LogIndex,
+    },
+    InstallSnapshot {
+        term: Term,
+        last_included_index: LogIndex,
+        last_included_term: Term,
+        members: Vec<NodeId>,
+        data: Snapshot,
+    },
+    InstallSnapshotRes {
+        term: Term,
+        last_index: LogIndex,
+    },
+}
@@ -0,0 +1,37 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{messages::raft::RaftMsg, node::NodeId};
+
+/// Counts the values broadcast by one origin, from 1.
+pub type Seq = u64;
+/// A position in the total order, from 0.
+pub type Position = u64;
+
+/// A broadcast value, identified by its origin and `seq`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Ordered<Val> {
+    pub origin: NodeId,
+    pub seq: Seq,
+    pub value: Val,
+}
+
+/// Messages between `TotalOrderBroadcast`s. Nothing is acknowledged:
+/// submissions are resent until they are ordered, and gaps in the order are
+/// pulled with `Sync`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "rpc", rename_all = "snake_case")]
+#[serde(bound(serialize = "Val: Serialize", deserialize = "Val: DeserializeOwned"))]
+pub enum TotalOrderMsg<Val> {
+    /// Asks the sequencer or the Raft leader to order a value.
+    Submit { entry: Ordered<Val> },
+    /// A run of the order, starting at `start`.
+    Deliver {
+        start: Position,
+        entries: Vec<Ordered<Val>>,
+    },
+    /// Asks the sequencer for the order from `next` on.
+    Sync { next: Position },
+    Raft {
+        raft: RaftMsg<Ordered<Val>, Vec<Ordered<Val>>>,
+    },
+}
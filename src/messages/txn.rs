@@ -0,0 +1,83 @@
+use serde::{
+    de::{DeserializeOwned, Error},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::MsgId;
+
+pub type TxnKey = u64;
+
+/// A single micro-operation of a Maelstrom transaction, encoded on the wire
+/// as a `[f, key, value]` triple, e.g. `["r", 1, null]` or `["w", 1, 3]`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MicroOp<Val = i64, ReadVal = Val> {
+    Read(TxnKey, Option<ReadVal>),
+    Write(TxnKey, Val),
+    Append(TxnKey, Val),
+}
+
+impl<Val, ReadVal> MicroOp<Val, ReadVal> {
+    pub fn key(self: &Self) -> TxnKey {
+        match self {
+            MicroOp::Read(key, _) | MicroOp::Write(key, _) | MicroOp::Append(key, _) => *key,
+        }
+    }
+}
+
+impl<Val, ReadVal> Serialize for MicroOp<Val, ReadVal>
+where
+    Val: Serialize,
+    ReadVal: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            MicroOp::Read(key, value) => ("r", key, value).serialize(serializer),
+            MicroOp::Write(key, value) => ("w", key, value).serialize(serializer),
+            MicroOp::Append(key, value) => ("append", key, value).serialize(serializer),
+        }
+    }
+}
+
+impl<'de, Val, ReadVal> Deserialize<'de> for MicroOp<Val, ReadVal>
+where
+    Val: DeserializeOwned,
+    ReadVal: DeserializeOwned,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (f, key, value) = <(String, TxnKey, serde_json::Value)>::deserialize(deserializer)?;
+
+        match f.as_str() {
+            "r" => serde_json::from_value(value).map(|v| MicroOp::Read(key, v)),
+            "w" => serde_json::from_value(value).map(|v| MicroOp::Write(key, v)),
+            "append" => serde_json::from_value(value).map(|v| MicroOp::Append(key, v)),
+            _ => return Err(D::Error::custom(format!("unknown micro-op '{}'", f))),
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound(
+    serialize = "Val: Serialize, ReadVal: Serialize",
+    deserialize = "Val: DeserializeOwned, ReadVal: DeserializeOwned"
+))]
+pub struct TxnBody<Val = i64, ReadVal = Val> {
+    pub msg_id: MsgId,
+    pub txn: Vec<MicroOp<Val, ReadVal>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound(
+    serialize = "Val: Serialize, ReadVal: Serialize",
+    deserialize = "Val: DeserializeOwned, ReadVal: DeserializeOwned"
+))]
+pub struct TxnOkBody<Val = i64, ReadVal = Val> {
+    pub in_reply_to: MsgId,
+    pub txn: Vec<MicroOp<Val, ReadVal>>,
+}
@@ -154,7 +154,7 @@ where
             return;
         };
 
-        let handler = if t.ends_with("_ok") && in_reply_to.is_some() {
+        let handler = if (t.ends_with("_ok") || t == "error") && in_reply_to.is_some() {
             self.callbacks
                 .lock()
                 .unwrap()
@@ -0,0 +1,802 @@
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap, HashSet, VecDeque,
+    },
+    fmt::Debug,
+    hash::{BuildHasher, Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use crate::messages::propagate::{PropagateMsg, PushId};
+use crate::{
+    compact::{NodeBitmap, ValueSet},
+    messages::Message,
+    node::{Node, NodeId},
+};
+
+/// Values are hashed into this many buckets for anti-entropy digests.
+const DIGEST_BUCKETS: usize = 64;
+/// Gossip is sent far more often, so it only carries the digests of this
+/// many coarser buckets, each folding together those equal modulo it.
+const GOSSIP_DIGEST_BUCKETS: usize = 8;
+
+/// Anything a `Propagator` can disseminate.
+pub trait PropagateVal:
+    Eq + Hash + Clone + Debug + Send + Sync + Serialize + DeserializeOwned + 'static
+{
+}
+
+impl<T> PropagateVal for T where
+    T: Eq + Hash + Clone + Debug + Send + Sync + Serialize + DeserializeOwned + 'static
+{
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Pushes values along the neighbours.
+    Propagate,
+    /// Pushes values to random peers, and pulls from peers that differ.
+    Gossip,
+    /// Pushes values along a spanning tree pruned out of the neighbours, and
+    /// only announces them over the other links.
+    Plumtree,
+}
+
+#[derive(Debug, Clone)]
+pub struct PropagateConfig {
+    pub mode: Mode,
+    /// How often new values are pushed, batched per neighbour.
+    pub push_interval: Duration,
+    /// How often unacknowledged values are pushed again.
+    pub resend_interval: Duration,
+    pub gossip_fanout: usize,
+    pub gossip_interval: Duration,
+    /// How long an announced value may be missing before it is grafted.
+    pub graft_timeout: Duration,
+    /// How often digests are compared with a random neighbour.
+    pub anti_entropy_interval: Duration,
+    /// Whether values are queued for `take_new_vals` as they first arrive.
+    pub collect_new_vals: bool,
+}
+
+impl Default for PropagateConfig {
+    fn default() -> Self {
+        PropagateConfig {
+            mode: Mode::Propagate,
+            push_interval: Duration::from_millis(200),
+            resend_interval: Duration::from_millis(1000),
+            gossip_fanout: 3,
+            gossip_interval: Duration::from_millis(100),
+            graft_timeout: Duration::from_millis(500),
+            anti_entropy_interval: Duration::from_millis(1000),
+            collect_new_vals: false,
+        }
+    }
+}
+
+/// A value some lazy peers have announced but that has not arrived yet.
+#[derive(Debug, Clone)]
+struct Missing {
+    /// Who announced it, in the order they are grafted from.
+    announcers: VecDeque<NodeId>,
+    deadline: Instant,
+}
+
+/// Reliably disseminates a grow-only set of values to every node. It does
+/// no I/O of its own: feed it messages with `step` and time with `tick`, and
+/// send whatever it queues with `flush`.
+pub struct Propagator<Val> {
+    id: NodeId,
+    node_ids: Vec<NodeId>,
+    config: PropagateConfig,
+    /// `None` until the node learns its neighbours; nothing is sent before.
+    neighbours: Option<Vec<NodeId>>,
+
+    values: HashSet<Val>,
+    /// XOR of the hashes of the values in each bucket of `bucket_of`, to
+    /// spot where peers differ.
+    bucket_digests: Vec<u64>,
+    /// Values not seen before, if `collect_new_vals` is set.
+    new_vals: Vec<Val>,
+
+    to_be_sent_vals: HashMap<NodeId, HashSet<Val>>,
+    /// Values sent to each peer that it has not acknowledged yet, by push.
+    unacked_vals: HashMap<NodeId, HashMap<PushId, HashSet<Val>>>,
+    /// Values each peer is known to have, so they are never sent to it again.
+    acked_vals: HashMap<NodeId, HashSet<Val>>,
+    next_push_id: PushId,
+
+    /// Values still being gossiped, with the rounds they were pushed in.
+    hot_vals: HashMap<Val, usize>,
+
+    /// Plumtree peers that values are pushed to.
+    eager_peers: HashSet<NodeId>,
+    /// Plumtree peers that values are only announced to.
+    lazy_peers: HashSet<NodeId>,
+    to_be_announced_vals: HashMap<NodeId, HashSet<Val>>,
+    missing_vals: HashMap<Val, Missing>,
+
+    push_deadline: Instant,
+    resend_deadline: Instant,
+    gossip_deadline: Instant,
+    anti_entropy_deadline: Instant,
+
+    outbox: Vec<(NodeId, PropagateMsg)>,
+}
+
+fn hash_val<Val: Hash>(val: &Val) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    val.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn bucket_of(hash: u64, buckets: usize) -> usize {
+    (hash % buckets as u64) as usize
+}
+
+fn decode_vals<Val: PropagateVal>(vals: &ValueSet) -> serde_json::Result<HashSet<Val>> {
+    Ok(vals.decode::<Val>()?.into_iter().collect())
+}
+
+/// Rounds a value is pushed for: enough for `fanout` pushes per round to
+/// reach all nodes with high probability.
+fn gossip_rounds(node_count: usize, fanout: usize) -> usize {
+    let reach = (node_count.max(2) as f64).ln() / (fanout.max(2) as f64).ln();
+    reach.ceil() as usize + 2
+}
+
+impl<Val> Propagator<Val>
+where
+    Val: PropagateVal,
+{
+    pub fn new(id: NodeId, node_ids: Vec<NodeId>, config: PropagateConfig) -> Self {
+        let now = Instant::now();
+
+        Propagator {
+            id,
+            node_ids,
+            neighbours: None,
+            values: HashSet::new(),
+            bucket_digests: vec![0; DIGEST_BUCKETS],
+            new_vals: Vec::new(),
+            to_be_sent_vals: HashMap::new(),
+            unacked_vals: HashMap::new(),
+            acked_vals: HashMap::new(),
+            next_push_id: 0,
+            hot_vals: HashMap::new(),
+            eager_peers: HashSet::new(),
+            lazy_peers: HashSet::new(),
+            to_be_announced_vals: HashMap::new(),
+            missing_vals: HashMap::new(),
+            push_deadline: now + config.push_interval,
+            resend_deadline: now + config.resend_interval,
+            gossip_deadline: now + config.gossip_interval,
+            anti_entropy_deadline: now + config.anti_entropy_interval,
+            config,
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn values(self: &Self) -> &HashSet<Val> {
+        &self.values
+    }
+
+    /// Sets who values are pushed to and synced with. Plumtree starts with
+    /// every neighbour eager and prunes down to a tree.
+    pub fn set_neighbours(self: &mut Self, neighbours: Vec<NodeId>) -> () {
+        self.eager_peers = neighbours.iter().cloned().collect();
+        self.lazy_peers.clear();
+        self.neighbours = Some(neighbours);
+    }
+
+    /// Adds values that originate at this node.
+    pub fn insert(self: &mut Self, vals: HashSet<Val>) -> () {
+        self.on_recv_val(vals, &HashSet::new());
+    }
+
+    pub fn take_outbox(self: &mut Self) -> Vec<(NodeId, PropagateMsg)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Values added since the last call, in the order they arrived.
+    pub fn take_new_vals(self: &mut Self) -> Vec<Val> {
+        std::mem::take(&mut self.new_vals)
+    }
+
+    fn ready(self: &Self) -> bool {
+        self.neighbours.is_some()
+    }
+
+    fn neighbours(self: &Self) -> &[NodeId] {
+        self.neighbours.as_deref().unwrap_or_default()
+    }
+
+    fn send(self: &mut Self, to: NodeId, msg: PropagateMsg) -> () {
+        self.outbox.push((to, msg));
+    }
+
+    fn add_new_val(self: &mut Self, val: Val) -> () {
+        if self.values.contains(&val) {
+            return;
+        }
+        let hash = hash_val(&val);
+        self.bucket_digests[bucket_of(hash, DIGEST_BUCKETS)] ^= hash;
+        if self.config.collect_new_vals {
+            self.new_vals.push(val.clone());
+        }
+        self.values.insert(val);
+    }
+
+    /// Values in the given buckets, out of `count`.
+    fn values_in(self: &Self, buckets: &HashSet<usize>, count: usize) -> HashSet<Val> {
+        self.values
+            .iter()
+            .filter(|&val| buckets.contains(&bucket_of(hash_val(val), count)))
+            .cloned()
+            .collect()
+    }
+
+    /// Nodes a push from this node need not be forwarded to. Plumtree peers
+    /// only skip the sender: its neighbours may hang off a different branch
+    /// of the tree.
+    fn known_nodes(self: &Self) -> HashSet<NodeId> {
+        let mut known_nodes: HashSet<NodeId> = match self.config.mode {
+            Mode::Plumtree => HashSet::new(),
+            _ => self.neighbours().iter().cloned().collect(),
+        };
+        known_nodes.insert(self.id.clone());
+        known_nodes
+    }
+
+    /// Picks up to `k` of `candidates` other than this node, uniformly at
+    /// random.
+    fn random_peers(self: &Self, candidates: &[NodeId], k: usize) -> Vec<NodeId> {
+        let random = RandomState::new();
+        let mut peers: Vec<&NodeId> = candidates.iter().filter(|&id| *id != self.id).collect();
+        peers.sort_by_cached_key(|id| random.hash_one(id));
+
+        peers.into_iter().take(k).cloned().collect()
+    }
+
+    fn on_recv_val(self: &mut Self, vals: HashSet<Val>, known_nodes: &HashSet<NodeId>) -> () {
+        for val in vals {
+            if self.values.contains(&val) {
+                continue;
+            }
+            self.add_new_val(val.clone());
+
+            match self.config.mode {
+                Mode::Gossip => {
+                    self.hot_vals.insert(val, 0);
+                }
+                Mode::Plumtree => {
+                    self.missing_vals.remove(&val);
+                    for peer in self.eager_peers.difference(known_nodes) {
+                        let to_be_sent_vals = self.to_be_sent_vals.entry(peer.clone());
+                        to_be_sent_vals.or_default().insert(val.clone());
+                    }
+                    for peer in self.lazy_peers.difference(known_nodes) {
+                        let to_be_announced_vals = self.to_be_announced_vals.entry(peer.clone());
+                        to_be_announced_vals.or_default().insert(val.clone());
+                    }
+                }
+                Mode::Propagate => {
+                    let neighbours = self.neighbours.as_deref().unwrap_or_default();
+                    for friend in neighbours.iter().filter(|&x| !known_nodes.contains(x)) {
+                        let to_be_sent_vals = self.to_be_sent_vals.entry(friend.clone());
+                        to_be_sent_vals.or_default().insert(val.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Messages with values this node cannot read are logged and dropped,
+    /// before they change anything.
+    pub fn step(self: &mut Self, from: &NodeId, msg: PropagateMsg) -> () {
+        self.step_at(from, msg, Instant::now())
+    }
+
+    pub fn step_at(self: &mut Self, from: &NodeId, msg: PropagateMsg, now: Instant) -> () {
+        if let Err(e) = self.step_msg(from, msg, now) {
+            eprintln!("bad propagate message from {}: {}", from, e);
+        }
+    }
+
+    fn step_msg(
+        self: &mut Self,
+        from: &NodeId,
+        msg: PropagateMsg,
+        now: Instant,
+    ) -> serde_json::Result<()> {
+        match msg {
+            PropagateMsg::Push {
+                id,
+                values,
+                known_nodes,
+            } => {
+                let values = decode_vals(&values)?;
+                let known_nodes = known_nodes.decode(&self.node_ids);
+                self.on_push(from, &values);
+                self.on_recv_val(values, &known_nodes);
+                self.send(from.clone(), PropagateMsg::PushOk { id });
+            }
+            PropagateMsg::PushOk { id } => {
+                // Acks of pushes a resend has coalesced away carry nothing
+                // new.
+                let Some(vals) = self
+                    .unacked_vals
+                    .get_mut(from)
+                    .and_then(|pushes| pushes.remove(&id))
+                else {
+                    return Ok(());
+                };
+                self.acked_vals
+                    .entry(from.clone())
+                    .or_default()
+                    .extend(vals);
+            }
+            PropagateMsg::Gossip { values, digests } => {
+                // Whatever `from` gossips, it has; the pull leaves it out.
+                let values = decode_vals(&values)?;
+                self.acked_vals
+                    .entry(from.clone())
+                    .or_default()
+                    .extend(values.iter().cloned());
+                self.on_recv_val(values, &HashSet::new());
+
+                let buckets = self.differing_buckets(&digests);
+                let acked_vals = &self.acked_vals[from];
+                let missing: HashSet<Val> = self
+                    .values_in(&buckets.into_iter().collect(), digests.len())
+                    .into_iter()
+                    .filter(|val| !acked_vals.contains(val))
+                    .collect();
+                if !missing.is_empty() {
+                    // Gossip has no acks, so sent values count as acked; a
+                    // lost reply is repaired by anti-entropy.
+                    let values = ValueSet::encode(&missing);
+                    self.acked_vals.get_mut(from).unwrap().extend(missing);
+                    self.send(from.clone(), PropagateMsg::GossipRes { values });
+                }
+            }
+            PropagateMsg::GossipRes { values } => {
+                self.on_recv_val(decode_vals(&values)?, &HashSet::new());
+            }
+            PropagateMsg::IHave { values } => {
+                let deadline = now + self.config.graft_timeout;
+                for val in decode_vals::<Val>(&values)? {
+                    if self.values.contains(&val) {
+                        continue;
+                    }
+                    let missing = self.missing_vals.entry(val).or_insert_with(|| Missing {
+                        announcers: VecDeque::new(),
+                        deadline,
+                    });
+                    if !missing.announcers.contains(from) {
+                        missing.announcers.push_back(from.clone());
+                    }
+                }
+            }
+            PropagateMsg::Graft { values } => {
+                let values: HashSet<Val> = decode_vals(&values)?;
+                self.make_eager(from);
+                let values = values.intersection(&self.values).cloned();
+                let to_be_sent_vals = self.to_be_sent_vals.entry(from.clone());
+                to_be_sent_vals.or_default().extend(values);
+            }
+            PropagateMsg::Prune => {
+                self.eager_peers.remove(from);
+                self.lazy_peers.insert(from.clone());
+            }
+            PropagateMsg::SyncDigest { digests } => {
+                let buckets = self.differing_buckets(&digests);
+                if buckets.is_empty() {
+                    return Ok(());
+                }
+                let values = self.values_in(&buckets.iter().cloned().collect(), DIGEST_BUCKETS);
+                let values = ValueSet::encode(&values);
+                self.send(
+                    from.clone(),
+                    PropagateMsg::SyncDigestRes { buckets, values },
+                );
+            }
+            PropagateMsg::SyncDigestRes { buckets, values } => {
+                let values = decode_vals(&values)?;
+                let mine = self.values_in(&buckets.into_iter().collect(), DIGEST_BUCKETS);
+                let missing: HashSet<Val> = mine.difference(&values).cloned().collect();
+
+                self.on_recv_val(values, &HashSet::from([from.clone()]));
+                if !missing.is_empty() {
+                    let values = ValueSet::encode(&missing);
+                    self.send(from.clone(), PropagateMsg::SyncValues { values });
+                }
+            }
+            PropagateMsg::SyncValues { values } => {
+                self.on_recv_val(decode_vals(&values)?, &HashSet::from([from.clone()]));
+            }
+        }
+        Ok(())
+    }
+
+    /// The bucket digests folded into `count` buckets; `count` must divide
+    /// `DIGEST_BUCKETS`.
+    fn digests(self: &Self, count: usize) -> Vec<u64> {
+        let mut digests = vec![0; count];
+        for (i, digest) in self.bucket_digests.iter().enumerate() {
+            digests[i % count] ^= digest;
+        }
+        digests
+    }
+
+    /// Buckets where a peer's digests differ from ours, out of as many as
+    /// it sent. Digests of a bucket count that does not divide
+    /// `DIGEST_BUCKETS` are ignored.
+    fn differing_buckets(self: &Self, digests: &[u64]) -> Vec<usize> {
+        let count = digests.len();
+        if count == 0 || !DIGEST_BUCKETS.is_multiple_of(count) {
+            return Vec::new();
+        }
+        let mine = self.digests(count);
+        (0..count).filter(|&i| digests[i] != mine[i]).collect()
+    }
+
+    /// Whatever `from` pushed, it has. In Plumtree, a push of known values
+    /// that `from` had not pushed before means they reached us over another
+    /// link too, so `from` is kept lazy from now on. Values it did push
+    /// before are resends of a push whose ack got lost, and say nothing
+    /// about the link.
+    fn on_push(self: &mut Self, from: &NodeId, values: &HashSet<Val>) -> () {
+        let acked_vals = self.acked_vals.entry(from.clone()).or_default();
+        let firsts: Vec<&Val> = values
+            .iter()
+            .filter(|val| !acked_vals.contains(*val))
+            .collect();
+        let has_new = firsts.iter().any(|val| !self.values.contains(*val));
+        let redundant = !firsts.is_empty() && !has_new;
+        acked_vals.extend(values.iter().cloned());
+
+        if self.config.mode != Mode::Plumtree {
+            return;
+        }
+        if has_new {
+            self.make_eager(from);
+        } else if redundant {
+            self.eager_peers.remove(from);
+            self.lazy_peers.insert(from.clone());
+            self.send(from.clone(), PropagateMsg::Prune);
+        }
+    }
+
+    fn make_eager(self: &mut Self, peer: &NodeId) -> () {
+        self.lazy_peers.remove(peer);
+        self.eager_peers.insert(peer.clone());
+    }
+
+    /// Drives pushes, resends, gossip and anti-entropy rounds; call it every
+    /// few milliseconds.
+    pub fn tick(self: &mut Self, now: Instant) -> () {
+        if !self.ready() {
+            return;
+        }
+
+        if now >= self.push_deadline {
+            self.push_deadline = now + self.config.push_interval;
+            self.push_to_friends();
+            if self.config.mode == Mode::Plumtree {
+                self.plumtree_round(now);
+            }
+        }
+        if now >= self.resend_deadline {
+            self.resend_deadline = now + self.config.resend_interval;
+            self.resend_unacked();
+        }
+        if self.config.mode == Mode::Gossip && now >= self.gossip_deadline {
+            self.gossip_deadline = now + self.config.gossip_interval;
+            self.gossip_round();
+        }
+        if now >= self.anti_entropy_deadline {
+            self.anti_entropy_deadline = now + self.config.anti_entropy_interval;
+            self.anti_entropy_round();
+        }
+    }
+
+    fn push_to_friends(self: &mut Self) -> () {
+        let known_nodes = self.known_nodes();
+
+        // Unacknowledged values are resent on their own, so each value only
+        // has to go out once.
+        for (friend_id, vals) in std::mem::take(&mut self.to_be_sent_vals) {
+            let vals: HashSet<Val> = match self.acked_vals.get(&friend_id) {
+                Some(acked) => vals.difference(acked).cloned().collect(),
+                None => vals,
+            };
+            if !vals.is_empty() {
+                self.push(friend_id, vals, &known_nodes);
+            }
+        }
+    }
+
+    /// Resends, per peer, everything it has not acknowledged as one push,
+    /// leaving out whatever it is known to have by now.
+    fn resend_unacked(self: &mut Self) -> () {
+        let known_nodes = self.known_nodes();
+
+        for (peer, pushes) in std::mem::take(&mut self.unacked_vals) {
+            let acked_vals = self.acked_vals.get(&peer);
+            let vals: HashSet<Val> = pushes
+                .into_values()
+                .flatten()
+                .filter(|val| !acked_vals.is_some_and(|acked| acked.contains(val)))
+                .collect();
+            if !vals.is_empty() {
+                self.push(peer, vals, &known_nodes);
+            }
+        }
+    }
+
+    fn push(
+        self: &mut Self,
+        friend_id: NodeId,
+        vals: HashSet<Val>,
+        known_nodes: &HashSet<NodeId>,
+    ) -> () {
+        self.next_push_id += 1;
+        let id = self.next_push_id;
+        let msg = PropagateMsg::Push {
+            id,
+            values: ValueSet::encode(&vals),
+            known_nodes: NodeBitmap::encode(known_nodes, &self.node_ids),
+        };
+        self.send(friend_id.clone(), msg);
+
+        self.unacked_vals
+            .entry(friend_id)
+            .or_default()
+            .insert(id, vals);
+    }
+
+    /// Announces new values to lazy peers, and grafts values that were
+    /// announced but have not been pushed in time.
+    fn plumtree_round(self: &mut Self, now: Instant) -> () {
+        for (peer, values) in std::mem::take(&mut self.to_be_announced_vals) {
+            let values = ValueSet::encode(&values);
+            self.send(peer, PropagateMsg::IHave { values });
+        }
+
+        let graft_timeout = self.config.graft_timeout;
+        let mut grafts: HashMap<NodeId, HashSet<Val>> = HashMap::new();
+        for (val, missing) in self.missing_vals.iter_mut() {
+            if missing.deadline > now {
+                continue;
+            }
+            // Try the next announcer on the following timeout, and come back
+            // to this one if none of them answer.
+            let announcer = missing.announcers.pop_front().unwrap();
+            grafts
+                .entry(announcer.clone())
+                .or_default()
+                .insert(val.clone());
+            missing.announcers.push_back(announcer);
+            missing.deadline = now + graft_timeout;
+        }
+
+        for (peer, values) in grafts {
+            self.make_eager(&peer);
+            let values = ValueSet::encode(&values);
+            self.send(peer, PropagateMsg::Graft { values });
+        }
+    }
+
+    fn gossip_round(self: &mut Self) -> () {
+        let rounds = gossip_rounds(self.node_ids.len(), self.config.gossip_fanout);
+        let values = ValueSet::encode(self.hot_vals.keys());
+        self.hot_vals.retain(|_, sent| {
+            *sent += 1;
+            *sent < rounds
+        });
+
+        for peer in self.random_peers(&self.node_ids, self.config.gossip_fanout) {
+            let msg = PropagateMsg::Gossip {
+                values: values.clone(),
+                digests: self.digests(GOSSIP_DIGEST_BUCKETS),
+            };
+            self.send(peer, msg);
+        }
+    }
+
+    /// Sends the bucket digests to one random neighbour; whatever either
+    /// side misses is exchanged in the replies.
+    fn anti_entropy_round(self: &mut Self) -> () {
+        for peer in self.random_peers(self.neighbours(), 1) {
+            let digests = self.bucket_digests.clone();
+            self.send(peer, PropagateMsg::SyncDigest { digests });
+        }
+    }
+}
+
+/// Sends everything queued by `propagator` through `node`, wrapping each
+/// message in the node's message body.
+pub fn flush<S, B, Val>(node: &Node<S, B>, propagator: &mut Propagator<Val>) -> ()
+where
+    S: Send,
+    B: Serialize + DeserializeOwned + Send + Clone + Debug + From<PropagateMsg>,
+    Val: PropagateVal,
+{
+    for (dest, msg) in propagator.take_outbox() {
+        node.send_msg(&Message {
+            src: node.node_id().clone(),
+            dest,
+            body: B::from(msg),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{node_ids, Network, Peer},
+        topology::{Strategy, Topology},
+    };
+
+    impl Peer for Propagator<u32> {
+        type Msg = PropagateMsg;
+
+        fn id(self: &Self) -> &NodeId {
+            &self.id
+        }
+
+        fn tick(self: &mut Self, now: Instant) -> () {
+            Propagator::tick(self, now)
+        }
+
+        fn step_at(self: &mut Self, from: &NodeId, msg: PropagateMsg, now: Instant) -> () {
+            Propagator::step_at(self, from, msg, now)
+        }
+
+        fn take_outbox(self: &mut Self) -> Vec<(NodeId, PropagateMsg)> {
+            Propagator::take_outbox(self)
+        }
+    }
+
+    type Cluster = Network<Propagator<u32>>;
+
+    /// Long enough that a mechanism never kicks in during a test.
+    const NEVER: Duration = Duration::from_secs(3600);
+
+    fn cluster(n: usize, strategy: Strategy, config: PropagateConfig) -> Cluster {
+        let ids = node_ids(n);
+        let topology = strategy.build(&ids, &Topology::new());
+        let nodes = ids
+            .iter()
+            .map(|id| {
+                let mut propagator = Propagator::new(id.clone(), ids.clone(), config.clone());
+                propagator.set_neighbours(topology[id].clone());
+                propagator
+            })
+            .collect();
+
+        Network::new(nodes)
+    }
+
+    fn has(cluster: &Cluster, id: &str, val: u32) -> bool {
+        cluster.get(id).values().contains(&val)
+    }
+
+    fn converges_despite_dropped_messages(mode: Mode) -> () {
+        let config = PropagateConfig {
+            mode,
+            ..PropagateConfig::default()
+        };
+        let mut cluster = cluster(6, Strategy::RingWithChords(0), config);
+        cluster.drop_every = Some(3);
+        for val in 0..20 {
+            cluster
+                .node(&format!("n{}", val % 6))
+                .insert(HashSet::from([val]));
+            cluster.run(Duration::from_millis(50));
+        }
+        cluster.run(Duration::from_secs(5));
+
+        let expected: HashSet<u32> = (0..20).collect();
+        for node in &cluster.nodes {
+            assert_eq!(node.values(), &expected, "values on {}", node.id);
+        }
+    }
+
+    #[test]
+    fn propagate_converges_despite_dropped_messages() {
+        converges_despite_dropped_messages(Mode::Propagate);
+    }
+
+    #[test]
+    fn gossip_converges_despite_dropped_messages() {
+        converges_despite_dropped_messages(Mode::Gossip);
+    }
+
+    #[test]
+    fn plumtree_converges_despite_dropped_messages() {
+        converges_despite_dropped_messages(Mode::Plumtree);
+    }
+
+    #[test]
+    fn plumtree_prunes_a_link_that_delivered_a_duplicate() {
+        let config = PropagateConfig {
+            mode: Mode::Plumtree,
+            ..PropagateConfig::default()
+        };
+        let mut cluster = cluster(3, Strategy::Mesh, config.clone());
+        let (n0, n1, n2) = ("n0".to_string(), "n1".to_string(), "n2".to_string());
+        let now = cluster.now + config.push_interval;
+
+        cluster.node(&n0).insert(HashSet::from([1]));
+        cluster.node(&n0).tick(now);
+        for (to, msg) in cluster.node(&n0).take_outbox() {
+            cluster.node(&to).step_at(&n0, msg, now);
+        }
+        cluster.node(&n2).take_outbox();
+
+        // n1 forwards what n0 pushed to n2, which already has it.
+        cluster.node(&n1).tick(now);
+        let pushes: Vec<(NodeId, PropagateMsg)> = cluster.node(&n1).take_outbox();
+        for (to, msg) in pushes.into_iter().filter(|(to, _)| *to == n2) {
+            cluster.node(&to).step_at(&n1, msg, now);
+        }
+
+        let outbox = cluster.node(&n2).take_outbox();
+        assert!(outbox
+            .iter()
+            .any(|(to, msg)| *to == n1 && matches!(msg, PropagateMsg::Prune)));
+        assert!(cluster.get(&n2).lazy_peers.contains(&n1));
+        assert!(cluster.get(&n2).eager_peers.contains(&n0));
+    }
+
+    #[test]
+    fn plumtree_grafts_a_value_announced_but_not_pushed() {
+        let config = PropagateConfig {
+            mode: Mode::Plumtree,
+            resend_interval: NEVER,
+            anti_entropy_interval: NEVER,
+            ..PropagateConfig::default()
+        };
+        let mut cluster = cluster(3, Strategy::Mesh, config.clone());
+        let (n1, n2) = ("n1".to_string(), "n2".to_string());
+        let now = cluster.now;
+        cluster.node(&n1).step_at(&n2, PropagateMsg::Prune, now);
+        cluster.node(&n2).step_at(&n1, PropagateMsg::Prune, now);
+
+        // n2 only hears of the value from n1, which keeps it lazy.
+        cluster.cut.insert(("n0".to_string(), n2.clone()));
+        cluster.node("n0").insert(HashSet::from([1]));
+        cluster.run(config.push_interval * 2 + config.graft_timeout / 2);
+        assert!(has(&cluster, "n1", 1));
+        assert!(!has(&cluster, "n2", 1));
+        assert!(cluster.get(&n2).missing_vals.contains_key(&1));
+
+        cluster.run(config.graft_timeout + config.push_interval);
+        assert!(has(&cluster, "n2", 1));
+        assert!(cluster.get(&n1).eager_peers.contains(&n2));
+        assert!(cluster.get(&n2).missing_vals.is_empty());
+    }
+
+    #[test]
+    fn anti_entropy_repairs_a_value_the_peer_missed() {
+        let config = PropagateConfig {
+            resend_interval: NEVER,
+            ..PropagateConfig::default()
+        };
+        let mut cluster = cluster(2, Strategy::Mesh, config.clone());
+        cluster.deaf.insert("n1".to_string());
+        cluster.node("n0").insert(HashSet::from([1]));
+        cluster.run(config.anti_entropy_interval * 3 / 2);
+        assert!(!has(&cluster, "n1", 1));
+
+        cluster.deaf.clear();
+        cluster.run(config.anti_entropy_interval * 3 / 2);
+        assert!(has(&cluster, "n1", 1));
+    }
+}
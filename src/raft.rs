@@ -0,0 +1,1028 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fmt::Debug,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use crate::messages::raft::{Entry, EntryKind, LogIndex, RaftMsg, Term};
+use crate::{
+    messages::Message,
+    node::{Node, NodeId},
+};
+
+/// The replicated application driven by Raft. Commands are applied in log
+/// order on every node; snapshots let the log be compacted.
+pub trait StateMachine {
+    type Command: Clone + Debug + Serialize + DeserializeOwned + Send;
+    type Output;
+    type Snapshot: Clone + Debug + Serialize + DeserializeOwned + Send;
+
+    fn apply(self: &mut Self, command: &Self::Command) -> Self::Output;
+    fn snapshot(self: &Self) -> Self::Snapshot;
+    fn restore(self: &mut Self, snapshot: Self::Snapshot) -> ();
+}
+
+pub type Msg<M> = RaftMsg<<M as StateMachine>::Command, <M as StateMachine>::Snapshot>;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub election_timeout: (Duration, Duration),
+    pub heartbeat_interval: Duration,
+    pub max_entries_per_append: usize,
+    /// Applied entries kept in the log before it is compacted into a
+    /// snapshot.
+    pub snapshot_threshold: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            election_timeout: (Duration::from_millis(300), Duration::from_millis(600)),
+            heartbeat_interval: Duration::from_millis(50),
+            max_entries_per_append: 64,
+            snapshot_threshold: 1024,
+        }
+    }
+}
+
+/// An entry that became committed and was applied on this node.
+#[derive(Debug)]
+pub struct Applied<Output> {
+    pub index: LogIndex,
+    pub term: Term,
+    /// `None` for no-op and membership entries.
+    pub output: Option<Output>,
+}
+
+#[derive(Debug)]
+pub enum ProposeError {
+    NotLeader(Option<NodeId>),
+    ConfigChangeInProgress,
+}
+
+#[derive(Debug)]
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<NodeId>,
+    },
+    Leader {
+        next_index: HashMap<NodeId, LogIndex>,
+        match_index: HashMap<NodeId, LogIndex>,
+    },
+}
+
+/// A Raft peer. It does no I/O itself: messages are fed in with `step`,
+/// time with `tick`, and whatever it wants to send or has applied is picked
+/// up with `take_outbox` and `take_applied`.
+///
+/// State is kept in memory only, so a node that crashes loses its vote and
+/// log and must not rejoin with the same id.
+pub struct Raft<M>
+where
+    M: StateMachine,
+{
+    id: NodeId,
+    config: RaftConfig,
+    machine: M,
+
+    current_term: Term,
+    voted_for: Option<NodeId>,
+    /// Entries after the snapshot; `log[0]` has index `snapshot_index + 1`.
+    log: Vec<Entry<M::Command>>,
+    snapshot_index: LogIndex,
+    snapshot_term: Term,
+    snapshot_members: Vec<NodeId>,
+    snapshot: Option<M::Snapshot>,
+
+    members: Vec<NodeId>,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+
+    role: Role,
+    leader: Option<NodeId>,
+    /// When the leader was last heard from, to ignore disruptive votes.
+    leader_contact: Option<Instant>,
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+
+    outbox: Vec<(NodeId, Msg<M>)>,
+    applied: Vec<Applied<M::Output>>,
+}
+
+fn random_between(min: Duration, max: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    let spread = (max - min).as_millis().max(1) as u64;
+
+    min + Duration::from_millis(hasher.finish() % spread)
+}
+
+impl<M> Raft<M>
+where
+    M: StateMachine,
+{
+    pub fn new(id: NodeId, members: Vec<NodeId>, machine: M, config: RaftConfig) -> Self {
+        let now = Instant::now();
+        let election_deadline =
+            now + random_between(config.election_timeout.0, config.election_timeout.1);
+
+        Raft {
+            id,
+            config,
+            machine,
+            current_term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot_members: members.clone(),
+            snapshot: None,
+            members,
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            leader_contact: None,
+            election_deadline,
+            heartbeat_deadline: now,
+            outbox: Vec::new(),
+            applied: Vec::new(),
+        }
+    }
+
+    pub fn id(self: &Self) -> &NodeId {
+        &self.id
+    }
+
+    pub fn term(self: &Self) -> Term {
+        self.current_term
+    }
+
+    pub fn is_leader(self: &Self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    pub fn leader(self: &Self) -> Option<&NodeId> {
+        self.leader.as_ref()
+    }
+
+    pub fn members(self: &Self) -> &Vec<NodeId> {
+        &self.members
+    }
+
+    pub fn commit_index(self: &Self) -> LogIndex {
+        self.commit_index
+    }
+
+    pub fn machine(self: &Self) -> &M {
+        &self.machine
+    }
+
+    pub fn take_outbox(self: &mut Self) -> Vec<(NodeId, Msg<M>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub fn take_applied(self: &mut Self) -> Vec<Applied<M::Output>> {
+        std::mem::take(&mut self.applied)
+    }
+
+    fn last_index(self: &Self) -> LogIndex {
+        self.snapshot_index + self.log.len() as LogIndex
+    }
+
+    fn last_term(self: &Self) -> Term {
+        self.log.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    fn term_at(self: &Self, index: LogIndex) -> Option<Term> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index || index > self.last_index() {
+            return None;
+        }
+        Some(self.log[(index - self.snapshot_index - 1) as usize].term)
+    }
+
+    fn peers(self: &Self) -> Vec<NodeId> {
+        self.members
+            .iter()
+            .filter(|&id| *id != self.id)
+            .cloned()
+            .collect()
+    }
+
+    fn quorum(self: &Self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    /// Whether a leader is known to be alive: this node leads, or heard
+    /// from the leader within the minimum election timeout.
+    fn leader_alive(self: &Self, now: Instant) -> bool {
+        self.is_leader()
+            || self
+                .leader_contact
+                .is_some_and(|at| now < at + self.config.election_timeout.0)
+    }
+
+    fn reset_election_deadline(self: &mut Self, now: Instant) -> () {
+        let (min, max) = self.config.election_timeout;
+        self.election_deadline = now + random_between(min, max);
+    }
+
+    fn send(self: &mut Self, to: &NodeId, msg: Msg<M>) -> () {
+        self.outbox.push((to.clone(), msg));
+    }
+
+    /// Members as of the latest config entry in the log, falling back to the
+    /// snapshot. Config entries take effect as soon as they are appended.
+    fn latest_members(self: &Self) -> Vec<NodeId> {
+        self.log
+            .iter()
+            .rev()
+            .find_map(|e| match &e.kind {
+                EntryKind::Config(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_members.clone())
+    }
+
+    fn append(self: &mut Self, entry: Entry<M::Command>) -> LogIndex {
+        if let EntryKind::Config(members) = &entry.kind {
+            self.members = members.clone();
+        }
+        self.log.push(entry);
+        self.last_index()
+    }
+
+    fn become_follower(self: &mut Self, term: Term, leader: Option<NodeId>) -> () {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+    }
+
+    fn become_leader(self: &mut Self, now: Instant) -> () {
+        let next = self.last_index() + 1;
+        let peers = self.peers();
+
+        self.role = Role::Leader {
+            next_index: peers.iter().map(|p| (p.clone(), next)).collect(),
+            match_index: peers.iter().map(|p| (p.clone(), 0)).collect(),
+        };
+        self.leader = Some(self.id.clone());
+
+        // A no-op from the new term lets entries of earlier terms commit.
+        self.append(Entry {
+            term: self.current_term,
+            kind: EntryKind::Noop,
+        });
+        self.broadcast_append(now);
+        self.advance_commit();
+    }
+
+    fn start_election(self: &mut Self, now: Instant) -> () {
+        self.current_term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id.clone()]),
+        };
+        self.reset_election_deadline(now);
+
+        if self.quorum() <= 1 {
+            return self.become_leader(now);
+        }
+
+        let (term, last_log_index, last_log_term) =
+            (self.current_term, self.last_index(), self.last_term());
+        for peer in self.peers() {
+            self.send(
+                &peer,
+                RaftMsg::RequestVote {
+                    term,
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+    }
+
+    /// Drives elections and heartbeats; call it every few milliseconds.
+    pub fn tick(self: &mut Self, now: Instant) -> () {
+        if self.is_leader() {
+            if now >= self.heartbeat_deadline {
+                self.broadcast_append(now);
+            }
+        } else if now >= self.election_deadline && self.members.contains(&self.id) {
+            self.start_election(now);
+        }
+    }
+
+    pub fn propose(self: &mut Self, command: M::Command) -> Result<(LogIndex, Term), ProposeError> {
+        self.propose_entry(EntryKind::Command(command))
+    }
+
+    /// Proposes a new member list. Only one member should be added or
+    /// removed at a time, and only once the previous change has committed.
+    pub fn propose_members(
+        self: &mut Self,
+        members: Vec<NodeId>,
+    ) -> Result<(LogIndex, Term), ProposeError> {
+        let pending = self.log
+            [(self.commit_index.max(self.snapshot_index) - self.snapshot_index) as usize..]
+            .iter()
+            .any(|e| matches!(e.kind, EntryKind::Config(_)));
+        if pending {
+            return Err(ProposeError::ConfigChangeInProgress);
+        }
+
+        let result = self.propose_entry(EntryKind::Config(members));
+        if let Role::Leader {
+            next_index,
+            match_index,
+        } = &mut self.role
+        {
+            let next = self.snapshot_index + self.log.len() as LogIndex;
+            for peer in self.members.iter().filter(|&id| *id != self.id) {
+                next_index.entry(peer.clone()).or_insert(next);
+                match_index.entry(peer.clone()).or_insert(0);
+            }
+        }
+
+        result
+    }
+
+    fn propose_entry(
+        self: &mut Self,
+        kind: EntryKind<M::Command>,
+    ) -> Result<(LogIndex, Term), ProposeError> {
+        if !self.is_leader() {
+            return Err(ProposeError::NotLeader(self.leader.clone()));
+        }
+
+        let term = self.current_term;
+        let index = self.append(Entry { term, kind });
+        self.advance_commit();
+
+        Ok((index, term))
+    }
+
+    fn broadcast_append(self: &mut Self, now: Instant) -> () {
+        self.heartbeat_deadline = now + self.config.heartbeat_interval;
+        for peer in self.peers() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn send_append(self: &mut Self, peer: &NodeId) -> () {
+        let Role::Leader { next_index, .. } = &self.role else {
+            return;
+        };
+        let next = *next_index.get(peer).unwrap_or(&(self.last_index() + 1));
+
+        if next <= self.snapshot_index {
+            let Some(data) = self.snapshot.clone() else {
+                return;
+            };
+            let msg = RaftMsg::InstallSnapshot {
+                term: self.current_term,
+                last_included_index: self.snapshot_index,
+                last_included_term: self.snapshot_term,
+                members: self.snapshot_members.clone(),
+                data,
+            };
+            return self.send(peer, msg);
+        }
+
+        let prev_log_index = next - 1;
+        let start = (next - self.snapshot_index - 1) as usize;
+        let end = (start + self.config.max_entries_per_append).min(self.log.len());
+        let msg = RaftMsg::AppendEntries {
+            term: self.current_term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap(),
+            entries: self.log[start..end].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        self.send(peer, msg);
+    }
+
+    pub fn step(self: &mut Self, from: &NodeId, msg: Msg<M>) -> () {
+        self.step_at(from, msg, Instant::now())
+    }
+
+    pub fn step_at(self: &mut Self, from: &NodeId, msg: Msg<M>, now: Instant) -> () {
+        let term = match &msg {
+            RaftMsg::RequestVote { term, .. }
+            | RaftMsg::RequestVoteRes { term, .. }
+            | RaftMsg::AppendEntries { term, .. }
+            | RaftMsg::AppendEntriesRes { term, .. }
+            | RaftMsg::InstallSnapshot { term, .. }
+            | RaftMsg::InstallSnapshotRes { term, .. } => *term,
+        };
+        // Vote requests are ignored while a leader is alive, so a member
+        // that was removed without learning it cannot depose the leader by
+        // timing out and bumping the term.
+        if matches!(msg, RaftMsg::RequestVote { .. }) && self.leader_alive(now) {
+            return;
+        }
+        if term > self.current_term {
+            self.become_follower(term, None);
+        }
+
+        match msg {
+            RaftMsg::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let vote_granted = term == self.current_term
+                    && up_to_date
+                    && self.voted_for.as_ref().is_none_or(|v| v == from);
+                if vote_granted {
+                    self.voted_for = Some(from.clone());
+                    self.reset_election_deadline(now);
+                }
+                let term = self.current_term;
+                self.send(from, RaftMsg::RequestVoteRes { term, vote_granted });
+            }
+            RaftMsg::RequestVoteRes { term, vote_granted } => {
+                let quorum = self.quorum();
+                let Role::Candidate { votes } = &mut self.role else {
+                    return;
+                };
+                if term != self.current_term || !vote_granted {
+                    return;
+                }
+                votes.insert(from.clone());
+                if votes.len() >= quorum {
+                    self.become_leader(now);
+                }
+            }
+            RaftMsg::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.current_term {
+                    let (term, last_index) = (self.current_term, self.last_index());
+                    return self.send(
+                        from,
+                        RaftMsg::AppendEntriesRes {
+                            term,
+                            success: false,
+                            last_index,
+                        },
+                    );
+                }
+                self.become_follower(term, Some(from.clone()));
+                self.leader_contact = Some(now);
+                self.reset_election_deadline(now);
+
+                let res =
+                    self.append_entries(prev_log_index, prev_log_term, entries, leader_commit);
+                let (term, last_index) = (self.current_term, res.unwrap_or_else(|l| l));
+                self.send(
+                    from,
+                    RaftMsg::AppendEntriesRes {
+                        term,
+                        success: res.is_ok(),
+                        last_index,
+                    },
+                );
+            }
+            RaftMsg::AppendEntriesRes {
+                term,
+                success,
+                last_index,
+            } => {
+                if term != self.current_term {
+                    return;
+                }
+                let Role::Leader {
+                    next_index,
+                    match_index,
+                } = &mut self.role
+                else {
+                    return;
+                };
+
+                if success {
+                    let matched = match_index.entry(from.clone()).or_insert(0);
+                    *matched = (*matched).max(last_index);
+                    next_index.insert(from.clone(), last_index + 1);
+                    self.advance_commit();
+                } else {
+                    let next = next_index.entry(from.clone()).or_insert(1);
+                    *next = (last_index + 1).min(next.saturating_sub(1)).max(1);
+                }
+
+                if last_index < self.last_index() || !success {
+                    self.send_append(from);
+                }
+            }
+            RaftMsg::InstallSnapshot {
+                term,
+                last_included_index,
+                last_included_term,
+                members,
+                data,
+            } => {
+                if term < self.current_term {
+                    let (term, last_index) = (self.current_term, self.last_index());
+                    return self.send(from, RaftMsg::InstallSnapshotRes { term, last_index });
+                }
+                self.become_follower(term, Some(from.clone()));
+                self.leader_contact = Some(now);
+                self.reset_election_deadline(now);
+
+                self.install_snapshot(last_included_index, last_included_term, members, data);
+                let (term, last_index) = (self.current_term, self.last_index());
+                self.send(from, RaftMsg::InstallSnapshotRes { term, last_index });
+            }
+            RaftMsg::InstallSnapshotRes { term, last_index } => {
+                if term != self.current_term {
+                    return;
+                }
+                let Role::Leader {
+                    next_index,
+                    match_index,
+                } = &mut self.role
+                else {
+                    return;
+                };
+                let matched = match_index.entry(from.clone()).or_insert(0);
+                *matched = (*matched).max(last_index);
+                next_index.insert(from.clone(), last_index + 1);
+                self.advance_commit();
+            }
+        }
+    }
+
+    /// Appends the leader's entries after `prev_log_index`. On success
+    /// returns the index of the last entry known to match the leader, on
+    /// failure a hint for where the leader should retry from.
+    fn append_entries(
+        self: &mut Self,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<Entry<M::Command>>,
+        leader_commit: LogIndex,
+    ) -> Result<LogIndex, LogIndex> {
+        if prev_log_index > self.last_index() {
+            return Err(self.last_index());
+        }
+
+        let mut index = prev_log_index;
+        let mut entries = entries.into_iter().peekable();
+        if prev_log_index < self.snapshot_index {
+            // Everything up to the snapshot is committed and known to match.
+            let skip = (self.snapshot_index - prev_log_index) as usize;
+            for _ in 0..skip {
+                if entries.next().is_none() {
+                    return Ok(self.snapshot_index);
+                }
+            }
+            index = self.snapshot_index;
+        } else if self.term_at(prev_log_index) != Some(prev_log_term) {
+            return Err(prev_log_index.saturating_sub(1).max(self.commit_index));
+        }
+
+        let mut truncated = false;
+        for entry in entries {
+            index += 1;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log
+                        .truncate((index - self.snapshot_index - 1) as usize);
+                    truncated = true;
+                    self.append(entry);
+                }
+                None => {
+                    self.append(entry);
+                }
+            }
+        }
+        if truncated {
+            self.members = self.latest_members();
+        }
+
+        if leader_commit > self.commit_index {
+            // A reordered request may cover less than is already committed.
+            self.commit_index = self.commit_index.max(leader_commit.min(index));
+            self.apply_committed();
+        }
+
+        Ok(index)
+    }
+
+    fn install_snapshot(
+        self: &mut Self,
+        last_included_index: LogIndex,
+        last_included_term: Term,
+        members: Vec<NodeId>,
+        data: M::Snapshot,
+    ) -> () {
+        if last_included_index <= self.commit_index {
+            return;
+        }
+
+        if self.term_at(last_included_index) == Some(last_included_term) {
+            self.log
+                .drain(..(last_included_index - self.snapshot_index) as usize);
+        } else {
+            self.log.clear();
+        }
+
+        self.snapshot_index = last_included_index;
+        self.snapshot_term = last_included_term;
+        self.snapshot_members = members;
+        self.machine.restore(data.clone());
+        self.snapshot = Some(data);
+        self.members = self.latest_members();
+        self.commit_index = last_included_index;
+        self.last_applied = last_included_index;
+    }
+
+    fn advance_commit(self: &mut Self) -> () {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+
+        let mut matched: Vec<LogIndex> = self
+            .members
+            .iter()
+            .map(|id| match id == &self.id {
+                true => self.last_index(),
+                false => *match_index.get(id).unwrap_or(&0),
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let Some(&majority) = matched.get(self.quorum() - 1) else {
+            return;
+        };
+        if majority > self.commit_index && self.term_at(majority) == Some(self.current_term) {
+            self.commit_index = majority;
+            self.apply_committed();
+        }
+
+        // A leader that removed itself steps down once the change commits.
+        if !self.members.contains(&self.id) && self.commit_index >= self.last_config_index() {
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+
+    fn last_config_index(self: &Self) -> LogIndex {
+        self.log
+            .iter()
+            .rposition(|e| matches!(e.kind, EntryKind::Config(_)))
+            .map_or(self.snapshot_index, |i| {
+                self.snapshot_index + i as LogIndex + 1
+            })
+    }
+
+    fn apply_committed(self: &mut Self) -> () {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[(self.last_applied - self.snapshot_index - 1) as usize];
+            let output = match &entry.kind {
+                EntryKind::Command(command) => Some(self.machine.apply(command)),
+                _ => None,
+            };
+
+            self.applied.push(Applied {
+                index: self.last_applied,
+                term: entry.term,
+                output,
+            });
+        }
+
+        if self.last_applied - self.snapshot_index > self.config.snapshot_threshold as LogIndex {
+            self.compact();
+        }
+    }
+
+    fn compact(self: &mut Self) -> () {
+        let compacted = (self.last_applied - self.snapshot_index) as usize;
+        let term = self.term_at(self.last_applied).unwrap();
+        let members = self.log[..compacted]
+            .iter()
+            .rev()
+            .find_map(|e| match &e.kind {
+                EntryKind::Config(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_members.clone());
+
+        self.log.drain(..compacted);
+        self.snapshot = Some(self.machine.snapshot());
+        self.snapshot_index = self.last_applied;
+        self.snapshot_term = term;
+        self.snapshot_members = members;
+    }
+}
+
+/// Sends everything queued by `raft` through `node`, wrapping each RPC in
+/// the node's message body.
+pub fn flush<S, B, M>(node: &Node<S, B>, raft: &mut Raft<M>) -> ()
+where
+    S: Send,
+    B: Serialize + DeserializeOwned + Send + Clone + Debug + From<Msg<M>>,
+    M: StateMachine,
+{
+    for (dest, msg) in raft.take_outbox() {
+        node.send_msg(&Message {
+            src: node.node_id().clone(),
+            dest,
+            body: B::from(msg),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{node_ids, Network, Peer};
+
+    /// Applies commands by recording them.
+    #[derive(Debug, Default)]
+    struct Record(Vec<u64>);
+
+    impl StateMachine for Record {
+        type Command = u64;
+        type Output = ();
+        type Snapshot = Vec<u64>;
+
+        fn apply(self: &mut Self, command: &u64) -> () {
+            self.0.push(*command);
+        }
+
+        fn snapshot(self: &Self) -> Vec<u64> {
+            self.0.clone()
+        }
+
+        fn restore(self: &mut Self, snapshot: Vec<u64>) -> () {
+            self.0 = snapshot;
+        }
+    }
+
+    impl Peer for Raft<Record> {
+        type Msg = Msg<Record>;
+
+        fn id(self: &Self) -> &NodeId {
+            Raft::id(self)
+        }
+
+        fn tick(self: &mut Self, now: Instant) -> () {
+            Raft::tick(self, now)
+        }
+
+        fn step_at(self: &mut Self, from: &NodeId, msg: Msg<Record>, now: Instant) -> () {
+            Raft::step_at(self, from, msg, now)
+        }
+
+        fn take_outbox(self: &mut Self) -> Vec<(NodeId, Msg<Record>)> {
+            Raft::take_outbox(self)
+        }
+
+        fn collect(self: &mut Self) -> () {
+            self.take_applied();
+        }
+    }
+
+    type Cluster = Network<Raft<Record>>;
+
+    fn cluster(n: usize, config: RaftConfig) -> Cluster {
+        let ids = node_ids(n);
+        let nodes = ids
+            .iter()
+            .map(|id| Raft::new(id.clone(), ids.clone(), Record::default(), config.clone()))
+            .collect();
+
+        Network::new(nodes)
+    }
+
+    impl Cluster {
+        fn leaders(self: &Self) -> Vec<NodeId> {
+            self.nodes
+                .iter()
+                .filter(|n| n.is_leader() && !self.isolated.contains(n.id()))
+                .map(|n| n.id().clone())
+                .collect()
+        }
+
+        fn leader(self: &mut Self) -> &mut Raft<Record> {
+            let leaders = self.leaders();
+            assert_eq!(leaders.len(), 1, "expected one leader, got {:?}", leaders);
+            self.node(&leaders[0])
+        }
+
+        fn records(self: &Self, id: &str) -> &Vec<u64> {
+            &self.get(id).machine().0
+        }
+    }
+
+    #[test]
+    fn elects_one_leader() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        cluster.run(Duration::from_secs(2));
+
+        let leader = cluster.leader().id().clone();
+        let term = cluster.leader().term();
+        for node in &cluster.nodes {
+            assert_eq!(node.leader(), Some(&leader));
+            assert_eq!(node.term(), term);
+        }
+    }
+
+    #[test]
+    fn reelects_when_leader_is_cut_off() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        cluster.run(Duration::from_secs(2));
+        let old = cluster.leader().id().clone();
+        let old_term = cluster.leader().term();
+
+        cluster.isolated.insert(old.clone());
+        cluster.run(Duration::from_secs(2));
+
+        let new = cluster.leader().id().clone();
+        assert_ne!(new, old);
+        assert!(cluster.node(&new).term() > old_term);
+    }
+
+    #[test]
+    fn commits_on_every_node() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        cluster.run(Duration::from_secs(2));
+
+        for command in 1..=5 {
+            cluster.leader().propose(command).unwrap();
+        }
+        cluster.run(Duration::from_millis(500));
+
+        for id in ["n0", "n1", "n2"] {
+            assert_eq!(cluster.records(id), &vec![1, 2, 3, 4, 5]);
+        }
+        let commit_index = cluster.leader().commit_index();
+        assert!(cluster
+            .nodes
+            .iter()
+            .all(|n| n.commit_index() == commit_index));
+    }
+
+    #[test]
+    fn stale_append_entries_keep_the_commit_index() {
+        let ids: Vec<NodeId> = ["n0", "n1", "n2"].map(String::from).into();
+        let mut follower = Raft::new(
+            "n1".to_string(),
+            ids,
+            Record::default(),
+            RaftConfig::default(),
+        );
+        let append = |prev_log_index: LogIndex, commands: &[u64], leader_commit: LogIndex| {
+            RaftMsg::AppendEntries {
+                term: 1,
+                prev_log_index,
+                prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+                entries: commands
+                    .iter()
+                    .map(|&c| Entry {
+                        term: 1,
+                        kind: EntryKind::Command(c),
+                    })
+                    .collect(),
+                leader_commit,
+            }
+        };
+        let leader = "n0".to_string();
+
+        follower.step(&leader, append(0, &[1, 2, 3, 4], 3));
+        assert_eq!(follower.commit_index(), 3);
+
+        // A retry capped to one entry, sent once the leader had committed
+        // more, that covers less than the follower already committed.
+        follower.step(&leader, append(0, &[1], 4));
+        assert_eq!(follower.commit_index(), 3);
+        assert_eq!(follower.machine().0, vec![1, 2, 3]);
+
+        follower.step(&leader, append(4, &[], 4));
+        assert_eq!(follower.commit_index(), 4);
+        assert_eq!(follower.machine().0, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn followers_refuse_proposals() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        cluster.run(Duration::from_secs(2));
+        let leader = cluster.leader().id().clone();
+
+        let follower = cluster.nodes.iter_mut().find(|n| !n.is_leader()).unwrap();
+        match follower.propose(1) {
+            Err(ProposeError::NotLeader(hint)) => assert_eq!(hint, Some(leader)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repairs_a_lagging_log() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        cluster.run(Duration::from_secs(2));
+        let lagging = cluster
+            .nodes
+            .iter()
+            .find(|n| !n.is_leader())
+            .unwrap()
+            .id()
+            .clone();
+
+        cluster.isolated.insert(lagging.clone());
+        for command in 1..=10 {
+            cluster.leader().propose(command).unwrap();
+        }
+        cluster.run(Duration::from_millis(500));
+        assert!(cluster.records(&lagging).is_empty());
+
+        cluster.isolated.clear();
+        cluster.run(Duration::from_secs(2));
+        assert_eq!(cluster.records(&lagging), &(1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn repairs_a_lagging_log_from_a_snapshot() {
+        let config = RaftConfig {
+            snapshot_threshold: 4,
+            ..RaftConfig::default()
+        };
+        let mut cluster = cluster(3, config);
+        cluster.run(Duration::from_secs(2));
+        let lagging = cluster
+            .nodes
+            .iter()
+            .find(|n| !n.is_leader())
+            .unwrap()
+            .id()
+            .clone();
+
+        cluster.isolated.insert(lagging.clone());
+        for command in 1..=20 {
+            cluster.leader().propose(command).unwrap();
+        }
+        cluster.run(Duration::from_millis(500));
+
+        cluster.isolated.clear();
+        cluster.run(Duration::from_secs(2));
+        assert_eq!(cluster.records(&lagging), &(1..=20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn removed_member_does_not_disrupt() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        cluster.run(Duration::from_secs(2));
+        let leader = cluster.leader().id().clone();
+        let removed = cluster
+            .nodes
+            .iter()
+            .find(|n| !n.is_leader())
+            .unwrap()
+            .id()
+            .clone();
+        let remaining: Vec<NodeId> = cluster
+            .nodes
+            .iter()
+            .map(|n| n.id().clone())
+            .filter(|id| *id != removed)
+            .collect();
+
+        // The removed member never hears about its removal, so it keeps
+        // timing out and asking for votes.
+        cluster.deaf.insert(removed.clone());
+        cluster.leader().propose_members(remaining.clone()).unwrap();
+        cluster.run(Duration::from_millis(500));
+        let term = cluster.leader().term();
+
+        cluster.run(Duration::from_secs(5));
+        assert!(cluster.node(&removed).term() > term);
+        assert_eq!(cluster.leader().id(), &leader);
+        assert_eq!(cluster.leader().term(), term);
+
+        cluster.leader().propose(7).unwrap();
+        cluster.run(Duration::from_millis(500));
+        for id in &remaining {
+            assert_eq!(cluster.records(id), &vec![7]);
+        }
+    }
+}
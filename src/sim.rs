@@ -0,0 +1,116 @@
+//! An in-memory network that drives sans-IO peers on a simulated clock, for
+//! tests.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::node::NodeId;
+
+/// How far the clock moves between two ticks.
+pub const STEP: Duration = Duration::from_millis(10);
+
+/// A sans-IO component the network can drive.
+pub trait Peer {
+    type Msg;
+
+    fn id(self: &Self) -> &NodeId;
+    fn tick(self: &mut Self, now: Instant) -> ();
+    fn step_at(self: &mut Self, from: &NodeId, msg: Self::Msg, now: Instant) -> ();
+    fn take_outbox(self: &mut Self) -> Vec<(NodeId, Self::Msg)>;
+    /// Picks up whatever the peer produced besides messages.
+    fn collect(self: &mut Self) -> () {}
+}
+
+pub fn node_ids(n: usize) -> Vec<NodeId> {
+    (0..n).map(|i| format!("n{}", i)).collect()
+}
+
+/// Peers passing messages in memory. Messages are delivered right away,
+/// unless one of the faults below drops them.
+pub struct Network<P> {
+    pub nodes: Vec<P>,
+    pub now: Instant,
+    /// Drops every this many messages, if set.
+    pub drop_every: Option<usize>,
+    sent: usize,
+    /// Nodes whose messages are dropped both ways.
+    pub isolated: HashSet<NodeId>,
+    /// Nodes that can send but receive nothing.
+    pub deaf: HashSet<NodeId>,
+    /// Links whose messages are dropped, from the first node to the second.
+    pub cut: HashSet<(NodeId, NodeId)>,
+}
+
+impl<P: Peer> Network<P> {
+    pub fn new(nodes: Vec<P>) -> Self {
+        Network {
+            nodes,
+            now: Instant::now(),
+            drop_every: None,
+            sent: 0,
+            isolated: HashSet::new(),
+            deaf: HashSet::new(),
+            cut: HashSet::new(),
+        }
+    }
+
+    pub fn node(self: &mut Self, id: &str) -> &mut P {
+        self.nodes.iter_mut().find(|n| n.id() == id).unwrap()
+    }
+
+    pub fn get(self: &Self, id: &str) -> &P {
+        self.nodes.iter().find(|n| n.id() == id).unwrap()
+    }
+
+    fn delivers(self: &mut Self, from: &NodeId, to: &NodeId) -> bool {
+        self.sent += 1;
+        if self
+            .drop_every
+            .is_some_and(|every| self.sent.is_multiple_of(every))
+        {
+            return false;
+        }
+        !self.isolated.contains(from)
+            && !self.isolated.contains(to)
+            && !self.deaf.contains(to)
+            && !self.cut.contains(&(from.clone(), to.clone()))
+    }
+
+    /// Passes messages around until nobody has anything left to send.
+    pub fn exchange(self: &mut Self) -> () {
+        loop {
+            let mut msgs = Vec::new();
+            for node in &mut self.nodes {
+                node.collect();
+                let from = node.id().clone();
+                msgs.extend(
+                    node.take_outbox()
+                        .into_iter()
+                        .map(|(to, m)| (from.clone(), to, m)),
+                );
+            }
+            if msgs.is_empty() {
+                break;
+            }
+            for (from, to, msg) in msgs {
+                if self.delivers(&from, &to) {
+                    let now = self.now;
+                    self.node(&to).step_at(&from, msg, now);
+                }
+            }
+        }
+    }
+
+    pub fn run(self: &mut Self, duration: Duration) -> () {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += STEP;
+            for node in &mut self.nodes {
+                node.tick(self.now);
+            }
+            self.exchange();
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use crate::node::NodeId;
+
+pub type Topology = HashMap<NodeId, Vec<NodeId>>;
+
+/// How a node picks its neighbours. Apart from `Given` and `SpanningTree`,
+/// which start from the topology Maelstrom suggests, every strategy is
+/// computed from the node ids alone, so all nodes agree on it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// The topology as Maelstrom sent it.
+    #[default]
+    Given,
+    /// Every node next to every other.
+    Mesh,
+    /// A BFS spanning tree of the given topology, rooted at the first node.
+    SpanningTree,
+    /// A complete tree where every node has up to `k` children.
+    KAryTree(usize),
+    /// The first `hubs` nodes form a mesh; every other node hangs off one.
+    Star(usize),
+    /// A ring where node `i` also links to `i + 2^j` for `j` in `1..=chords`.
+    RingWithChords(usize),
+    /// A graph where every node has about `degree` neighbours, picked by a
+    /// pseudo-random generator seeded from the node ids.
+    RandomRegular(usize),
+}
+
+#[derive(Debug)]
+pub struct ParseStrategyError(String);
+
+impl fmt::Display for ParseStrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown topology strategy {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseStrategyError {}
+
+/// Parses `given`, `mesh`, `spanning-tree`, `tree:<k>`, `star:<hubs>`,
+/// `ring:<chords>` and `random:<degree>`.
+impl FromStr for Strategy {
+    type Err = ParseStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseStrategyError(s.to_string());
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg.parse::<usize>().map_err(|_| err())?)),
+            None => (s, None),
+        };
+
+        match (name, arg) {
+            ("given", None) => Ok(Strategy::Given),
+            ("mesh", None) => Ok(Strategy::Mesh),
+            ("spanning-tree", None) => Ok(Strategy::SpanningTree),
+            ("tree", Some(k)) if k > 0 => Ok(Strategy::KAryTree(k)),
+            ("star", Some(hubs)) if hubs > 0 => Ok(Strategy::Star(hubs)),
+            ("ring", Some(chords)) => Ok(Strategy::RingWithChords(chords)),
+            ("random", Some(degree)) if degree > 0 => Ok(Strategy::RandomRegular(degree)),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Collects undirected edges between node indices into a topology.
+struct Edges {
+    neighbours: Vec<BTreeSet<usize>>,
+}
+
+impl Edges {
+    fn new(n: usize) -> Self {
+        Edges {
+            neighbours: vec![BTreeSet::new(); n],
+        }
+    }
+
+    fn add(self: &mut Self, a: usize, b: usize) -> () {
+        if a != b {
+            self.neighbours[a].insert(b);
+            self.neighbours[b].insert(a);
+        }
+    }
+
+    fn into_topology(self: Self, node_ids: &[NodeId]) -> Topology {
+        self.neighbours
+            .into_iter()
+            .enumerate()
+            .map(|(i, neighbours)| {
+                let neighbours = neighbours.into_iter().map(|j| node_ids[j].clone());
+                (node_ids[i].clone(), neighbours.collect())
+            })
+            .collect()
+    }
+}
+
+/// splitmix64, so every node derives the same graph from the same seed.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(self: &mut Self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle<T>(self: &mut Self, items: &mut [T]) -> () {
+        for i in (1..items.len()).rev() {
+            items.swap(i, (self.next() % (i as u64 + 1)) as usize);
+        }
+    }
+}
+
+impl Strategy {
+    /// Builds the neighbours of every node in `node_ids`.
+    pub fn build(self: &Self, node_ids: &[NodeId], given: &Topology) -> Topology {
+        let mut node_ids = node_ids.to_vec();
+        node_ids.sort();
+        let n = node_ids.len();
+        let mut edges = Edges::new(n);
+
+        match *self {
+            Strategy::Given => return given.clone(),
+            Strategy::Mesh => {
+                for a in 0..n {
+                    for b in a + 1..n {
+                        edges.add(a, b);
+                    }
+                }
+            }
+            Strategy::SpanningTree => {
+                let index: HashMap<&NodeId, usize> =
+                    node_ids.iter().enumerate().map(|(i, id)| (id, i)).collect();
+                let mut seen = vec![false; n];
+                let mut queue = VecDeque::new();
+                for root in 0..n {
+                    if seen[root] {
+                        continue;
+                    }
+                    seen[root] = true;
+                    queue.push_back(root);
+
+                    while let Some(a) = queue.pop_front() {
+                        let neighbours = given.get(&node_ids[a]).into_iter().flatten();
+                        for b in neighbours.filter_map(|id| index.get(id)) {
+                            if !seen[*b] {
+                                seen[*b] = true;
+                                edges.add(a, *b);
+                                queue.push_back(*b);
+                            }
+                        }
+                    }
+                    // Nodes the given topology leaves out join the tree at
+                    // the root.
+                    if root > 0 {
+                        edges.add(0, root);
+                    }
+                }
+            }
+            Strategy::KAryTree(k) => {
+                for child in 1..n {
+                    edges.add((child - 1) / k, child);
+                }
+            }
+            Strategy::Star(hubs) => {
+                let hubs = hubs.min(n);
+                for a in 0..hubs {
+                    for b in a + 1..hubs {
+                        edges.add(a, b);
+                    }
+                }
+                for leaf in hubs..n {
+                    edges.add(leaf % hubs, leaf);
+                }
+            }
+            Strategy::RingWithChords(chords) => {
+                for a in 0..n {
+                    edges.add(a, (a + 1) % n);
+                    for j in 1..=chords.min(usize::BITS as usize - 1) {
+                        edges.add(a, (a + (1 << j)) % n);
+                    }
+                }
+            }
+            Strategy::RandomRegular(degree) => {
+                let mut hasher = DefaultHasher::new();
+                node_ids.hash(&mut hasher);
+                let mut rng = SplitMix(hasher.finish());
+
+                // Each random cycle adds two neighbours per node; the first
+                // one also keeps the graph connected.
+                let mut order: Vec<usize> = (0..n).collect();
+                for _ in 0..degree.div_ceil(2) {
+                    rng.shuffle(&mut order);
+                    for i in 0..n {
+                        edges.add(order[i], order[(i + 1) % n]);
+                    }
+                }
+            }
+        }
+
+        edges.into_topology(&node_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(n: usize) -> Vec<NodeId> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    /// A line through the nodes, as Maelstrom might suggest.
+    fn line(node_ids: &[NodeId]) -> Topology {
+        let mut given = Topology::new();
+        for pair in node_ids.windows(2) {
+            given
+                .entry(pair[0].clone())
+                .or_default()
+                .push(pair[1].clone());
+            given
+                .entry(pair[1].clone())
+                .or_default()
+                .push(pair[0].clone());
+        }
+        given
+    }
+
+    fn assert_symmetric_and_connected(strategy: &Strategy, topology: &Topology, n: usize) -> () {
+        assert_eq!(topology.len(), n, "{:?} with {} nodes", strategy, n);
+        for (a, neighbours) in topology {
+            assert!(
+                !neighbours.contains(a),
+                "{:?}: {} links to itself",
+                strategy,
+                a
+            );
+            for b in neighbours {
+                assert!(
+                    topology[b].contains(a),
+                    "{:?}: {} -> {} is one way",
+                    strategy,
+                    a,
+                    b
+                );
+            }
+        }
+
+        let Some(start) = topology.keys().next() else {
+            return;
+        };
+        let mut seen = BTreeSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(a) = queue.pop_front() {
+            for b in &topology[a] {
+                if seen.insert(b) {
+                    queue.push_back(b);
+                }
+            }
+        }
+        assert_eq!(seen.len(), n, "{:?} with {} nodes is split", strategy, n);
+    }
+
+    #[test]
+    fn strategies_are_symmetric_and_connected() {
+        let strategies = [
+            Strategy::Mesh,
+            Strategy::SpanningTree,
+            Strategy::KAryTree(1),
+            Strategy::KAryTree(3),
+            Strategy::Star(1),
+            Strategy::Star(3),
+            Strategy::RingWithChords(0),
+            Strategy::RingWithChords(2),
+            Strategy::RandomRegular(1),
+            Strategy::RandomRegular(4),
+        ];
+        for n in [1, 2, 3, 5, 8, 25] {
+            let ids = node_ids(n);
+            for strategy in &strategies {
+                let topology = strategy.build(&ids, &line(&ids));
+                assert_symmetric_and_connected(strategy, &topology, n);
+            }
+        }
+    }
+
+    #[test]
+    fn spanning_tree_covers_nodes_the_given_topology_leaves_out() {
+        let ids = node_ids(6);
+        let topology = Strategy::SpanningTree.build(&ids, &line(&ids[..3]));
+        assert_symmetric_and_connected(&Strategy::SpanningTree, &topology, 6);
+        let edges: usize = topology.values().map(|n| n.len()).sum();
+        assert_eq!(edges, 2 * 5);
+    }
+
+    #[test]
+    fn trees_and_stars_bound_the_degrees() {
+        let ids = node_ids(25);
+        let topology = Strategy::KAryTree(3).build(&ids, &Topology::new());
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(topology[&sorted[0]].len(), 3);
+        assert!(topology.values().all(|n| n.len() <= 4));
+        let edges: usize = topology.values().map(|n| n.len()).sum();
+        assert_eq!(edges, 2 * 24);
+
+        let topology = Strategy::Star(3).build(&ids, &Topology::new());
+        let (hubs, leaves) = sorted.split_at(3);
+        for hub in hubs {
+            // The other hubs, and a third of the leaves each.
+            assert!((2 + 7..=2 + 8).contains(&topology[hub].len()));
+        }
+        for leaf in leaves {
+            assert_eq!(topology[leaf].len(), 1);
+            assert!(hubs.contains(&topology[leaf][0]));
+        }
+    }
+
+    #[test]
+    fn random_regular_is_the_same_on_every_node() {
+        let ids = node_ids(25);
+        let mut reversed = ids.clone();
+        reversed.reverse();
+
+        let strategy = Strategy::RandomRegular(4);
+        let topology = strategy.build(&ids, &Topology::new());
+        assert_eq!(topology, strategy.build(&reversed, &line(&ids)));
+        assert!(topology.values().all(|n| (2..=4).contains(&n.len())));
+    }
+
+    #[test]
+    fn parses_strategies() {
+        let parsed = [
+            ("given", Strategy::Given),
+            ("mesh", Strategy::Mesh),
+            ("spanning-tree", Strategy::SpanningTree),
+            ("tree:2", Strategy::KAryTree(2)),
+            ("star:1", Strategy::Star(1)),
+            ("ring:0", Strategy::RingWithChords(0)),
+            ("random:3", Strategy::RandomRegular(3)),
+        ];
+        for (s, strategy) in parsed {
+            assert_eq!(s.parse::<Strategy>().unwrap(), strategy);
+        }
+
+        for s in [
+            "tree:0", "tree", "star:0", "random:0", "ring", "mesh:2", "tree:x", "line", "",
+        ] {
+            assert!(s.parse::<Strategy>().is_err(), "{:?} parsed", s);
+        }
+    }
+}
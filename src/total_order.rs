@@ -0,0 +1,556 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use crate::messages::total_order::{Ordered, Position, Seq, TotalOrderMsg};
+use crate::{
+    messages::Message,
+    node::{Node, NodeId},
+    propagate::PropagateVal,
+    raft::{Raft, RaftConfig, StateMachine},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The lowest node id orders every value. Cheap, but nothing is ordered
+    /// while the sequencer is down or cut off.
+    Sequencer,
+    /// Values are ordered by a Raft log, so a majority keeps making
+    /// progress.
+    Consensus,
+}
+
+#[derive(Debug, Clone)]
+pub struct TotalOrderConfig {
+    pub mode: Mode,
+    /// How often values broadcast here are submitted again until they are
+    /// ordered.
+    pub resend_interval: Duration,
+    /// How often nodes ask the sequencer for the rest of the order.
+    pub sync_interval: Duration,
+    pub max_entries_per_deliver: usize,
+    pub raft: RaftConfig,
+}
+
+impl Default for TotalOrderConfig {
+    fn default() -> Self {
+        TotalOrderConfig {
+            mode: Mode::Sequencer,
+            resend_interval: Duration::from_millis(500),
+            sync_interval: Duration::from_millis(200),
+            max_entries_per_deliver: 256,
+            raft: RaftConfig::default(),
+        }
+    }
+}
+
+/// The total order so far, as a state machine so Raft can replicate it.
+/// Values ordered twice, after a resend, only count the first time.
+#[derive(Debug)]
+struct OrderLog<Val> {
+    entries: Vec<Ordered<Val>>,
+    ids: HashSet<(NodeId, Seq)>,
+}
+
+impl<Val> OrderLog<Val> {
+    fn new() -> Self {
+        OrderLog {
+            entries: Vec::new(),
+            ids: HashSet::new(),
+        }
+    }
+}
+
+impl<Val: PropagateVal> StateMachine for OrderLog<Val> {
+    type Command = Ordered<Val>;
+    type Output = ();
+    /// The whole order, since a node that restores it must still deliver
+    /// every value in it.
+    type Snapshot = Vec<Ordered<Val>>;
+
+    fn apply(self: &mut Self, entry: &Ordered<Val>) -> () {
+        if self.ids.insert((entry.origin.clone(), entry.seq)) {
+            self.entries.push(entry.clone());
+        }
+    }
+
+    fn snapshot(self: &Self) -> Self::Snapshot {
+        self.entries.clone()
+    }
+
+    fn restore(self: &mut Self, entries: Self::Snapshot) -> () {
+        self.ids = entries
+            .iter()
+            .map(|entry| (entry.origin.clone(), entry.seq))
+            .collect();
+        self.entries = entries;
+    }
+}
+
+enum Engine<Val: PropagateVal> {
+    Sequencer {
+        log: OrderLog<Val>,
+        /// Entries that arrived ahead of a gap in the order.
+        early: BTreeMap<Position, Ordered<Val>>,
+    },
+    Consensus(Box<Raft<OrderLog<Val>>>),
+}
+
+/// Delivers every broadcast value to every node in the same order. Like
+/// `Propagator` it does no I/O of its own: feed it messages with `step` and
+/// time with `tick`, send what it queues with `flush`, and pick up values
+/// with `take_delivered`.
+pub struct TotalOrderBroadcast<Val: PropagateVal> {
+    id: NodeId,
+    node_ids: Vec<NodeId>,
+    sequencer: NodeId,
+    config: TotalOrderConfig,
+    engine: Engine<Val>,
+
+    next_seq: Seq,
+    /// Values broadcast here that have not been ordered yet.
+    pending: BTreeMap<Seq, Val>,
+    /// How much of the order has been handed to `delivered`.
+    delivered_count: usize,
+    delivered: Vec<Ordered<Val>>,
+
+    resend_deadline: Instant,
+    sync_deadline: Instant,
+
+    outbox: Vec<(NodeId, TotalOrderMsg<Val>)>,
+}
+
+impl<Val> TotalOrderBroadcast<Val>
+where
+    Val: PropagateVal,
+{
+    pub fn new(id: NodeId, node_ids: Vec<NodeId>, config: TotalOrderConfig) -> Self {
+        let now = Instant::now();
+        let sequencer = node_ids.iter().min().unwrap_or(&id).clone();
+        let engine = match config.mode {
+            Mode::Sequencer => Engine::Sequencer {
+                log: OrderLog::new(),
+                early: BTreeMap::new(),
+            },
+            Mode::Consensus => Engine::Consensus(Box::new(Raft::new(
+                id.clone(),
+                node_ids.clone(),
+                OrderLog::new(),
+                config.raft.clone(),
+            ))),
+        };
+
+        TotalOrderBroadcast {
+            id,
+            node_ids,
+            sequencer,
+            engine,
+            next_seq: 1,
+            pending: BTreeMap::new(),
+            delivered_count: 0,
+            delivered: Vec::new(),
+            resend_deadline: now + config.resend_interval,
+            sync_deadline: now + config.sync_interval,
+            config,
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn take_outbox(self: &mut Self) -> Vec<(NodeId, TotalOrderMsg<Val>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Values ordered since the last call, in the order every node
+    /// delivers them.
+    pub fn take_delivered(self: &mut Self) -> Vec<Ordered<Val>> {
+        std::mem::take(&mut self.delivered)
+    }
+
+    pub fn broadcast(self: &mut Self, value: Val) -> () {
+        let entry = Ordered {
+            origin: self.id.clone(),
+            seq: self.next_seq,
+            value,
+        };
+        self.next_seq += 1;
+        self.pending.insert(entry.seq, entry.value.clone());
+
+        self.submit(entry);
+        self.deliver();
+    }
+
+    pub fn step(self: &mut Self, from: &NodeId, msg: TotalOrderMsg<Val>) -> () {
+        self.step_at(from, msg, Instant::now())
+    }
+
+    pub fn step_at(self: &mut Self, from: &NodeId, msg: TotalOrderMsg<Val>, now: Instant) -> () {
+        match msg {
+            TotalOrderMsg::Submit { entry } => self.submit(entry),
+            TotalOrderMsg::Deliver { start, entries } => self.on_deliver(start, entries),
+            TotalOrderMsg::Sync { next } => self.on_sync(from, next),
+            TotalOrderMsg::Raft { raft: msg } => {
+                if let Engine::Consensus(raft) = &mut self.engine {
+                    raft.step_at(from, msg, now);
+                }
+            }
+        }
+
+        self.drain_raft();
+        self.deliver();
+    }
+
+    pub fn tick(self: &mut Self, now: Instant) -> () {
+        if let Engine::Consensus(raft) = &mut self.engine {
+            raft.tick(now);
+        }
+
+        if now >= self.sync_deadline {
+            self.sync_deadline = now + self.config.sync_interval;
+            if let Engine::Sequencer { log, .. } = &self.engine {
+                if self.id != self.sequencer {
+                    let next = log.entries.len() as Position;
+                    self.send(self.sequencer.clone(), TotalOrderMsg::Sync { next });
+                }
+            }
+        }
+
+        if now >= self.resend_deadline {
+            self.resend_deadline = now + self.config.resend_interval;
+            let pending: Vec<Ordered<Val>> = self
+                .pending
+                .iter()
+                .map(|(&seq, value)| Ordered {
+                    origin: self.id.clone(),
+                    seq,
+                    value: value.clone(),
+                })
+                .collect();
+            for entry in pending {
+                self.submit(entry);
+            }
+        }
+
+        self.drain_raft();
+        self.deliver();
+    }
+
+    fn send(self: &mut Self, to: NodeId, msg: TotalOrderMsg<Val>) -> () {
+        self.outbox.push((to, msg));
+    }
+
+    fn log(self: &Self) -> &OrderLog<Val> {
+        match &self.engine {
+            Engine::Sequencer { log, .. } => log,
+            Engine::Consensus(raft) => raft.machine(),
+        }
+    }
+
+    /// Hands an entry to whoever orders it. Submissions that get lost, or
+    /// that a leader accepts but never commits, are covered by resends.
+    fn submit(self: &mut Self, entry: Ordered<Val>) -> () {
+        match &mut self.engine {
+            Engine::Sequencer { .. } if self.id != self.sequencer => {
+                self.send(self.sequencer.clone(), TotalOrderMsg::Submit { entry });
+            }
+            Engine::Sequencer { log, .. } => {
+                let start = log.entries.len() as Position;
+                log.apply(&entry);
+                if log.entries.len() as Position == start {
+                    return;
+                }
+                for peer in self.node_ids.clone() {
+                    if peer != self.id {
+                        let entries = vec![entry.clone()];
+                        self.send(peer, TotalOrderMsg::Deliver { start, entries });
+                    }
+                }
+            }
+            Engine::Consensus(raft) => {
+                if raft.is_leader() {
+                    let _ = raft.propose(entry);
+                } else if let Some(leader) = raft.leader().cloned() {
+                    self.send(leader, TotalOrderMsg::Submit { entry });
+                }
+            }
+        }
+    }
+
+    fn on_deliver(self: &mut Self, start: Position, entries: Vec<Ordered<Val>>) -> () {
+        let Engine::Sequencer { log, early } = &mut self.engine else {
+            return;
+        };
+
+        for (i, entry) in entries.into_iter().enumerate() {
+            let position = start + i as Position;
+            if position >= log.entries.len() as Position {
+                early.insert(position, entry);
+            }
+        }
+        while let Some(entry) = early.remove(&(log.entries.len() as Position)) {
+            log.apply(&entry);
+        }
+    }
+
+    fn on_sync(self: &mut Self, from: &NodeId, next: Position) -> () {
+        let entries: Vec<Ordered<Val>> = self
+            .log()
+            .entries
+            .iter()
+            .skip(next as usize)
+            .take(self.config.max_entries_per_deliver)
+            .cloned()
+            .collect();
+        if !entries.is_empty() {
+            self.send(
+                from.clone(),
+                TotalOrderMsg::Deliver {
+                    start: next,
+                    entries,
+                },
+            );
+        }
+    }
+
+    /// Wraps whatever Raft queued, and drops its applied entries: the order
+    /// is read from the log itself.
+    fn drain_raft(self: &mut Self) -> () {
+        let Engine::Consensus(raft) = &mut self.engine else {
+            return;
+        };
+
+        raft.take_applied();
+        for (to, msg) in raft.take_outbox() {
+            self.outbox.push((to, TotalOrderMsg::Raft { raft: msg }));
+        }
+    }
+
+    fn deliver(self: &mut Self) -> () {
+        let new_entries: Vec<Ordered<Val>> = self.log().entries[self.delivered_count..].to_vec();
+        self.delivered_count += new_entries.len();
+
+        for entry in new_entries.iter().filter(|entry| entry.origin == self.id) {
+            self.pending.remove(&entry.seq);
+        }
+        self.delivered.extend(new_entries);
+    }
+}
+
+/// Sends everything queued by `total_order` through `node`.
+pub fn flush<S, B, Val>(node: &Node<S, B>, total_order: &mut TotalOrderBroadcast<Val>) -> ()
+where
+    S: Send,
+    B: Serialize + DeserializeOwned + Send + Clone + Debug + From<TotalOrderMsg<Val>>,
+    Val: PropagateVal,
+{
+    for (dest, msg) in total_order.take_outbox() {
+        node.send_msg(&Message {
+            src: node.node_id().clone(),
+            dest,
+            body: B::from(msg),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{node_ids, Network, Peer, STEP};
+
+    /// A broadcast instance and what it delivered so far.
+    struct Member {
+        tob: TotalOrderBroadcast<u32>,
+        delivered: Vec<Ordered<u32>>,
+    }
+
+    impl Peer for Member {
+        type Msg = TotalOrderMsg<u32>;
+
+        fn id(self: &Self) -> &NodeId {
+            &self.tob.id
+        }
+
+        fn tick(self: &mut Self, now: Instant) -> () {
+            self.tob.tick(now)
+        }
+
+        fn step_at(self: &mut Self, from: &NodeId, msg: TotalOrderMsg<u32>, now: Instant) -> () {
+            self.tob.step_at(from, msg, now)
+        }
+
+        fn take_outbox(self: &mut Self) -> Vec<(NodeId, TotalOrderMsg<u32>)> {
+            self.tob.take_outbox()
+        }
+
+        fn collect(self: &mut Self) -> () {
+            self.delivered.extend(self.tob.take_delivered());
+        }
+    }
+
+    type Cluster = Network<Member>;
+
+    fn cluster(n: usize, mode: Mode) -> Cluster {
+        let ids = node_ids(n);
+        let config = TotalOrderConfig {
+            mode,
+            ..TotalOrderConfig::default()
+        };
+        let nodes = ids
+            .iter()
+            .map(|id| Member {
+                tob: TotalOrderBroadcast::new(id.clone(), ids.clone(), config.clone()),
+                delivered: Vec::new(),
+            })
+            .collect();
+
+        Network::new(nodes)
+    }
+
+    impl Cluster {
+        fn broadcast(self: &mut Self, id: &str, value: u32) -> () {
+            self.node(id).tob.broadcast(value);
+            self.exchange();
+        }
+
+        fn values(self: &Self, id: &str) -> Vec<u32> {
+            self.get(id)
+                .delivered
+                .iter()
+                .map(|entry| entry.value)
+                .collect()
+        }
+
+        /// Asserts that every node delivered exactly `values`, in one order.
+        fn assert_agree(self: &Self, values: impl IntoIterator<Item = u32>) -> () {
+            let mut expected: Vec<u32> = values.into_iter().collect();
+            expected.sort_unstable();
+            let mut order = self.values("n0");
+            for node in &self.nodes {
+                let id = &node.tob.id;
+                assert_eq!(self.values(id), order, "order on {}", id);
+            }
+            order.sort_unstable();
+            assert_eq!(order, expected);
+        }
+    }
+
+    fn orders_values_from_every_node(mode: Mode) -> () {
+        let mut cluster = cluster(3, mode);
+        cluster.run(Duration::from_secs(2));
+        for v in 0..30 {
+            cluster.broadcast(&format!("n{}", v % 3), v);
+        }
+        cluster.run(Duration::from_secs(2));
+
+        cluster.assert_agree(0..30);
+    }
+
+    fn orders_values_despite_dropped_messages(mode: Mode) -> () {
+        let mut cluster = cluster(3, mode);
+        cluster.drop_every = Some(4);
+        for v in 0..30 {
+            cluster.broadcast(&format!("n{}", v % 3), v);
+            cluster.run(STEP * 5);
+        }
+        cluster.run(Duration::from_secs(5));
+        cluster.drop_every = None;
+        cluster.run(Duration::from_secs(2));
+
+        cluster.assert_agree(0..30);
+    }
+
+    #[test]
+    fn sequencer_orders_values_from_every_node() {
+        orders_values_from_every_node(Mode::Sequencer);
+    }
+
+    #[test]
+    fn consensus_orders_values_from_every_node() {
+        orders_values_from_every_node(Mode::Consensus);
+    }
+
+    #[test]
+    fn sequencer_orders_values_despite_dropped_messages() {
+        orders_values_despite_dropped_messages(Mode::Sequencer);
+    }
+
+    #[test]
+    fn consensus_orders_values_despite_dropped_messages() {
+        orders_values_despite_dropped_messages(Mode::Consensus);
+    }
+
+    #[test]
+    fn sequencer_resends_lost_submissions() {
+        let mut cluster = cluster(3, Mode::Sequencer);
+        cluster.isolated.insert("n0".to_string());
+        cluster.broadcast("n1", 1);
+        cluster.broadcast("n2", 2);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.values("n1"), Vec::<u32>::new());
+
+        cluster.isolated.clear();
+        cluster.run(Duration::from_secs(1));
+        cluster.assert_agree([1, 2]);
+    }
+
+    #[test]
+    fn sequencer_gaps_are_filled_by_sync() {
+        let mut cluster = cluster(3, Mode::Sequencer);
+        cluster.deaf.insert("n2".to_string());
+        cluster.broadcast("n0", 1);
+        cluster.deaf.clear();
+        cluster.broadcast("n1", 2);
+
+        // n2 holds 2 back until it learns what came before it.
+        assert_eq!(cluster.values("n1"), vec![1, 2]);
+        assert_eq!(cluster.values("n2"), Vec::<u32>::new());
+        cluster.run(cluster.nodes[0].tob.config.sync_interval * 2);
+        cluster.assert_agree([1, 2]);
+    }
+
+    #[test]
+    fn consensus_resends_values_broadcast_before_a_leader_is_known() {
+        let mut cluster = cluster(3, Mode::Consensus);
+        cluster.broadcast("n0", 1);
+        cluster.broadcast("n1", 2);
+        cluster.broadcast("n2", 3);
+        cluster.run(Duration::from_secs(3));
+
+        cluster.assert_agree([1, 2, 3]);
+    }
+
+    #[test]
+    fn consensus_keeps_ordering_while_the_leader_is_cut_off() {
+        let mut cluster = cluster(3, Mode::Consensus);
+        cluster.run(Duration::from_secs(2));
+        let leader = cluster
+            .nodes
+            .iter()
+            .find_map(|n| match &n.tob.engine {
+                Engine::Consensus(raft) if raft.is_leader() => Some(n.tob.id.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let others: Vec<NodeId> = cluster
+            .nodes
+            .iter()
+            .map(|n| n.tob.id.clone())
+            .filter(|id| id != &leader)
+            .collect();
+
+        cluster.isolated.insert(leader.clone());
+        cluster.broadcast(&leader, 1);
+        cluster.broadcast(&others[0], 2);
+        cluster.broadcast(&others[1], 3);
+        cluster.run(Duration::from_secs(3));
+        assert_eq!(cluster.values(&others[0]).len(), 2);
+        assert_eq!(cluster.values(&others[0]), cluster.values(&others[1]));
+
+        cluster.isolated.clear();
+        cluster.run(Duration::from_secs(3));
+        cluster.assert_agree([1, 2, 3]);
+    }
+}